@@ -0,0 +1,149 @@
+//! Loads the bunny, copied over and over into a model of about a million
+//! vertices, on one and on several threads, and parses its vertices with
+//! `str::parse` as the parser did before it was optimized. Run with
+//! `cargo bench`.
+#![allow(dead_code)]
+
+#[macro_use]
+extern crate glium;
+extern crate nalgebra as na;
+extern crate flate2;
+extern crate memmap;
+extern crate crossbeam;
+
+#[path = "../src/obj.rs"]
+mod obj;
+#[path = "../src/mtl.rs"]
+mod mtl;
+#[path = "../src/float.rs"]
+mod float;
+#[path = "../src/fnv.rs"]
+mod fnv;
+#[path = "../src/normals.rs"]
+mod normals;
+#[path = "../src/triangulate.rs"]
+mod triangulate;
+
+use std::env;
+use std::f64;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use na::Vec3;
+
+const COPIES: usize = 400;
+const RUNS: usize = 3;
+
+fn main() {
+    let path = scaled_bunny();
+    let size = fs::metadata(&path).unwrap().len() as f64 / 1e6;
+    println!("{}: {:.0} MB", path.display(), size);
+
+    let mut data = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+    let numbers = data.split(|&b| b == b'\n')
+                      .filter(|line| line.starts_with(b"v"))
+                      .flat_map(|line| line.split(|&b| b == b' ').skip(1))
+                      .map(|number| String::from_utf8(number.to_vec()).unwrap())
+                      .collect::<Vec<_>>();
+    // Summed up and printed, so that the parsing is not optimized away.
+    let mut sum = 0.0;
+    report("str::parse", size, || {
+        sum += numbers.iter().map(|s| s.parse::<f32>().unwrap()).fold(0.0, |a, b| a + b);
+    });
+    report("float::parse", size, || {
+        sum -= numbers.iter().map(|s| float::parse(s).unwrap()).fold(0.0, |a, b| a + b);
+    });
+    println!("{:>32}: {}", "difference", sum);
+
+    let text = String::from_utf8(data.clone()).unwrap();
+    let vertices = text.lines().filter(|line| line.starts_with("v ")).collect::<Vec<_>>();
+    report("previous parse_vec", size, || {
+        sum += vertices.iter().map(|line| previous_parse_vec(line).x).fold(0.0, |a, b| a + b);
+    });
+    report("parse_vec", size, || {
+        sum -= vertices.iter()
+                       .map(|&line| {
+                           let line = obj::Line { number: 1, text: line };
+                           obj::parse_vec(line, line.text.split_whitespace().skip(1)).unwrap().x
+                       })
+                       .fold(0.0, |a, b| a + b);
+    });
+    println!("{:>32}: {}", "difference", sum);
+
+    report("parse_reader", size, || {
+        let reader = BufReader::new(File::open(&path).unwrap());
+        obj::parse_reader(reader, Path::new(""), &Default::default()).unwrap();
+    });
+    for &threads in &[1, 2, 4, 8] {
+        let options = obj::LoadOptions { threads: threads, ..Default::default() };
+        report(&format!("parse_bytes, {} threads", threads), size, || {
+            obj::parse_bytes(&data, Path::new(""), &options).unwrap();
+        });
+    }
+}
+
+/// Parses `v x y z` with `str::parse`, as the parser did before it was
+/// optimized.
+fn previous_parse_vec(line: &str) -> Vec3<f32> {
+    let coords = line.split_whitespace()
+                     .skip(1)
+                     .map(|word| word.parse::<f32>().unwrap())
+                     .collect::<Vec<_>>();
+    assert_eq!(coords.len(), 3);
+    Vec3::new(coords[0], coords[1], coords[2])
+}
+
+/// Prints the best of a few runs.
+fn report<F: FnMut()>(name: &str, size: f64, mut f: F) {
+    let best = (0..RUNS).map(|_| {
+                            let start = Instant::now();
+                            f();
+                            seconds(start.elapsed())
+                        })
+                        .fold(f64::INFINITY, f64::min);
+    println!("{:>32}: {:8.1} ms, {:6.1} MB/s", name, best * 1e3, size / best);
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+/// Writes `COPIES` of the bunny side by side, unless they are already there.
+fn scaled_bunny() -> PathBuf {
+    let path = env::temp_dir().join(format!("bunny_x{}.obj", COPIES));
+    if path.exists() {
+        return path;
+    }
+
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("bunny_with_normals.obj");
+    let lines = BufReader::new(File::open(source).unwrap()).lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+    let count = |keyword: &str| lines.iter().filter(|l| l.split_whitespace().next() == Some(keyword)).count();
+    let (positions, normals) = (count("v"), count("vn"));
+
+    let mut out = BufWriter::new(File::create(&path).unwrap());
+    for copy in 0..COPIES {
+        for line in &lines {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.first() {
+                Some(&"v") => {
+                    let x = words[1].parse::<f32>().unwrap() + 0.2 * copy as f32;
+                    writeln!(out, "v {} {} {}", x, words[2], words[3]).unwrap();
+                }
+                Some(&"vn") => writeln!(out, "{}", line).unwrap(),
+                Some(&"f") => {
+                    write!(out, "f").unwrap();
+                    for vertex in &words[1..] {
+                        let ids = vertex.split("//").map(|i| i.parse::<usize>().unwrap()).collect::<Vec<_>>();
+                        write!(out, " {}//{}", ids[0] + copy * positions, ids[1] + copy * normals).unwrap();
+                    }
+                    writeln!(out, "").unwrap();
+                }
+                _ => (),
+            }
+        }
+    }
+    path
+}
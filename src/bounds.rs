@@ -0,0 +1,59 @@
+//! Bounds of models, for placing the camera.
+
+use na::{self, Vec3};
+
+/// An axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>,
+}
+
+impl Aabb {
+    /// The box around the points, `None` if there are none.
+    pub fn of<I: IntoIterator<Item = Vec3<f32>>>(points: I) -> Option<Aabb> {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(p) => p,
+            None => return None,
+        };
+        Some(points.fold(Aabb { min: first, max: first }, |aabb, p| aabb.add(p)))
+    }
+
+    /// The smallest box holding this one and the point.
+    pub fn add(self, p: Vec3<f32>) -> Aabb {
+        Aabb {
+            min: Vec3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z)),
+            max: Vec3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z)),
+        }
+    }
+
+    pub fn center(&self) -> Vec3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3<f32> {
+        self.max - self.min
+    }
+}
+
+/// A sphere holding a set of points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub center: Vec3<f32>,
+    pub radius: f32,
+}
+
+impl Sphere {
+    /// The sphere around the center of the bounding box of the points.
+    /// It is not the smallest one, but it is never larger than the sphere
+    /// through the corners of the box, and finding it takes a single pass.
+    pub fn around<I: IntoIterator<Item = Vec3<f32>>>(aabb: &Aabb, points: I) -> Sphere {
+        let center = aabb.center();
+        let squared = points.into_iter().map(|p| na::sqnorm(&(p - center))).fold(0.0, f32::max);
+        Sphere {
+            center: center,
+            radius: squared.sqrt(),
+        }
+    }
+}
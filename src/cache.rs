@@ -0,0 +1,605 @@
+//! A binary copy of a parsed model, kept next to the source file, which
+//! loads in a fraction of the time it takes to parse the source.
+//!
+//! The cache holds the buffers of `obj::Obj` as they are laid out in
+//! memory, so it is only valid for the build which wrote it. It is
+//! rewritten whenever the version, the memory layout, the load options or
+//! the source do not match. Other files the source refers to, like material
+//! libraries or glTF buffers, are not tracked, the cache has to be deleted
+//! after editing them.
+//!
+//! The cache is read rather than mapped, straight into the buffers of the
+//! model, as those have to be owned anyway. The source is read in full to
+//! hash it whenever a cache is written, and when it was touched since: a
+//! cache which is not writable is checked that way on every load.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::u64;
+
+use na::Vec3;
+
+use fnv::FnvHasher;
+use mtl::{Material, TextureMap};
+use normals::NormalMode;
+use obj::{self, Obj, Indices, MaterialRange, Group, LoadOptions, Vertex, Normal, TexCoord, Color};
+
+/// `try!` for `Option`.
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(v) => v, None => return None })
+}
+
+const MAGIC: &'static [u8; 8] = b"BUNNYMSH";
+
+/// Bump when the format changes, or when the parser starts to give
+/// different results for the same source.
+const VERSION: u32 = 3;
+
+/// The cache of `model.obj` is `model.obj.cache`.
+pub fn cache_path(source: &Path) -> PathBuf {
+    let mut name = source.file_name().map(|name| name.to_owned()).unwrap_or_else(OsString::new);
+    name.push(".cache");
+    source.with_file_name(name)
+}
+
+/// Loads the model from its cache if it is up to date, otherwise loads it
+/// with `load` and writes the cache. Failing to write the cache is not an
+/// error, and models loaded with warnings are not cached.
+pub fn load_cached<F>(path: &Path, options: &LoadOptions, load: F) -> obj::Result<Obj>
+    where F: FnOnce(&Path, &LoadOptions) -> obj::Result<Obj>
+{
+    let cache = cache_path(path);
+    let mut source = try!(Source::read(path));
+    if let Some(obj) = load_cache(&cache, &mut source, options) {
+        return Ok(obj);
+    }
+
+    let obj = try!(load(path, options));
+    if obj.warnings.is_empty() {
+        let _ = save(&obj, &cache, &mut source, options);
+    }
+    Ok(obj)
+}
+
+// `Metadata::modified` is only stable since Rust 1.10.
+
+#[cfg(unix)]
+fn modified(metadata: &fs::Metadata) -> (u64, u32) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.mtime() as u64, metadata.mtime_nsec() as u32)
+}
+
+#[cfg(windows)]
+fn modified(metadata: &fs::Metadata) -> (u64, u32) {
+    use std::os::windows::fs::MetadataExt;
+    // Intervals of 100 nanoseconds since 1601.
+    let time = metadata.last_write_time();
+    (time / 10000000, (time % 10000000) as u32 * 100)
+}
+
+/// What the cache knows about the source it was made from.
+struct Source {
+    path: PathBuf,
+    len: u64,
+    /// Modification time, as seconds and nanoseconds since the epoch of
+    /// the platform.
+    modified: (u64, u32),
+    /// FNV-1a of the contents, only computed when the modification time
+    /// does not match, as hashing a big file is not free either.
+    hash: Option<u64>,
+}
+
+impl Source {
+    fn read(path: &Path) -> io::Result<Source> {
+        let metadata = try!(fs::metadata(path));
+        Ok(Source {
+            path: path.to_owned(),
+            len: metadata.len(),
+            modified: modified(&metadata),
+            hash: None,
+        })
+    }
+
+    fn hash(&mut self) -> io::Result<u64> {
+        if let Some(hash) = self.hash {
+            return Ok(hash);
+        }
+        let mut hasher = FnvHasher::default();
+        let mut reader = BufReader::with_capacity(1 << 16, try!(File::open(&self.path)));
+        loop {
+            let len = {
+                let buffer = try!(reader.fill_buf());
+                hasher.write(buffer);
+                buffer.len()
+            };
+            if len == 0 {
+                break;
+            }
+            reader.consume(len);
+        }
+        let hash = hasher.finish();
+        self.hash = Some(hash);
+        Ok(hash)
+    }
+}
+
+/// Sizes of the vertex types, which are stored as they are in memory.
+fn layout() -> [u32; 4] {
+    [mem::size_of::<Vertex>() as u32,
+     mem::size_of::<Normal>() as u32,
+     mem::size_of::<TexCoord>() as u32,
+     mem::size_of::<Color>() as u32]
+}
+
+/// The parts of `LoadOptions` which change the result: the normal mode
+/// and the crease angle.
+fn normal_mode(options: &LoadOptions) -> [f32; 2] {
+    match options.normals {
+        NormalMode::Flat => [0.0, 0.0],
+        NormalMode::AreaWeighted => [1.0, 0.0],
+        NormalMode::AngleWeighted => [2.0, 0.0],
+        NormalMode::Creased(angle) => [3.0, angle],
+    }
+}
+
+/// Returns `None` if the cache is missing, stale or damaged.
+fn load_cache(path: &Path, source: &mut Source, options: &LoadOptions) -> Option<Obj> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    let len = try_opt!(file.metadata().ok()).len();
+    let mut reader = Reader {
+        input: BufReader::new(file),
+        offset: 0,
+        len: len,
+    };
+
+    if reader.pod::<[u8; 8]>() != Some(*MAGIC) || reader.pod::<u32>() != Some(VERSION) ||
+       reader.pod::<[u32; 4]>() != Some(layout()) || reader.pod::<[f32; 2]>() != Some(normal_mode(options)) ||
+       reader.pod::<u64>() != Some(source.len) {
+        return None;
+    }
+    let modified_offset = reader.offset;
+    let modified = (try_opt!(reader.pod::<u64>()), try_opt!(reader.pod::<u32>()));
+    let hash = try_opt!(reader.pod::<u64>());
+    if modified != source.modified && source.hash().ok() != Some(hash) {
+        return None;
+    }
+
+    let dir = source.path.parent().unwrap_or(Path::new(""));
+    let obj = Obj {
+        vertices: try_opt!(reader.vec()),
+        normals: try_opt!(reader.vec()),
+        tex_coords: try_opt!(reader.vec()),
+        colors: try_opt!(reader.vec()),
+        indices: try_opt!(reader.indices()),
+        edges: try_opt!(reader.indices()),
+        lines: try_opt!(reader.indices()),
+        points: try_opt!(reader.indices()),
+        materials: try_opt!(reader.list(|r| r.material(dir))),
+        material_ranges: try_opt!(reader.list(|r| {
+            let range = try_opt!(r.pod::<[u64; 3]>());
+            Some(MaterialRange {
+                material: if range[0] == u64::MAX { None } else { Some(range[0] as usize) },
+                range: range[1] as usize..range[2] as usize,
+            })
+        })),
+        groups: try_opt!(reader.list(|r| {
+            let object = try_opt!(r.string());
+            let name = try_opt!(r.string());
+            let range = try_opt!(r.pod::<[u64; 2]>());
+            Some(Group {
+                object: object,
+                name: name,
+                range: range[0] as usize..range[1] as usize,
+            })
+        })),
+        warnings: Vec::new(),
+    };
+    if reader.offset as u64 != reader.len || obj.validate().is_err() || !ranges_valid(&obj) {
+        return None;
+    }
+    // The source was touched but not changed. Storing its new time spares
+    // hashing it again next time.
+    if modified != source.modified {
+        let _ = write_modified(path, modified_offset, source.modified);
+    }
+    Some(obj)
+}
+
+/// Whether the material ranges and the groups cover whole triangles, and
+/// refer to existing materials, as drawing them relies on it.
+fn ranges_valid(obj: &Obj) -> bool {
+    let count = obj.indices.len();
+    let valid = |r: &Range<usize>| r.start <= r.end && r.end <= count && r.start % 3 == 0 && r.end % 3 == 0;
+    obj.material_ranges.iter().all(|r| valid(&r.range) && r.material.map_or(true, |m| m < obj.materials.len())) &&
+    obj.groups.iter().all(|g| valid(&g.range))
+}
+
+/// Overwrites the modification time of the source in the header of the cache.
+fn write_modified(path: &Path, offset: usize, modified: (u64, u32)) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().write(true).open(path));
+    try!(file.seek(SeekFrom::Start(offset as u64)));
+    let mut writer = Writer {
+        out: file,
+        offset: offset,
+    };
+    try!(writer.pod(modified.0));
+    writer.pod(modified.1)
+}
+
+/// Writes the cache to a temporary file first, so that an interrupted
+/// write never leaves a damaged cache behind.
+fn save(obj: &Obj, path: &Path, source: &mut Source, options: &LoadOptions) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let result = write_cache(obj, &temp, source, options).and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_cache(obj: &Obj, path: &Path, source: &mut Source, options: &LoadOptions) -> io::Result<()> {
+    // Created first, so that the source is not hashed in vain.
+    let mut writer = Writer {
+        out: BufWriter::new(try!(File::create(path))),
+        offset: 0,
+    };
+    let hash = try!(source.hash());
+
+    try!(writer.bytes(MAGIC));
+    try!(writer.pod(VERSION));
+    try!(writer.pod(layout()));
+    try!(writer.pod(normal_mode(options)));
+    try!(writer.pod(source.len));
+    try!(writer.pod(source.modified.0));
+    try!(writer.pod(source.modified.1));
+    try!(writer.pod(hash));
+
+    try!(writer.vec(&obj.vertices));
+    try!(writer.vec(&obj.normals));
+    try!(writer.vec(&obj.tex_coords));
+    try!(writer.vec(&obj.colors));
+    for indices in &[&obj.indices, &obj.edges, &obj.lines, &obj.points] {
+        try!(writer.indices(indices));
+    }
+
+    let dir = source.path.parent().unwrap_or(Path::new(""));
+    try!(writer.pod(obj.materials.len() as u64));
+    for material in &obj.materials {
+        try!(writer.material(material, dir));
+    }
+    try!(writer.pod(obj.material_ranges.len() as u64));
+    for range in &obj.material_ranges {
+        let material = range.material.map_or(u64::MAX, |m| m as u64);
+        try!(writer.pod([material, range.range.start as u64, range.range.end as u64]));
+    }
+    try!(writer.pod(obj.groups.len() as u64));
+    for group in &obj.groups {
+        try!(writer.string(&group.object));
+        try!(writer.string(&group.name));
+        try!(writer.pod([group.range.start as u64, group.range.end as u64]));
+    }
+    writer.out.flush()
+}
+
+/// Everything is padded to eight bytes, which keeps the stored types
+/// aligned within the file.
+const ALIGN: usize = 8;
+
+struct Writer<W: Write> {
+    out: W,
+    offset: usize,
+}
+
+impl<W: Write> Writer<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        try!(self.out.write_all(bytes));
+        self.offset += bytes.len();
+        let padding = (ALIGN - self.offset % ALIGN) % ALIGN;
+        try!(self.out.write_all(&[0; ALIGN][..padding]));
+        self.offset += padding;
+        Ok(())
+    }
+
+    /// Writes plain data, which must contain neither pointers nor padding.
+    fn slice<T: Copy>(&mut self, data: &[T]) -> io::Result<()> {
+        self.bytes(unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * mem::size_of::<T>()) })
+    }
+
+    fn pod<T: Copy>(&mut self, value: T) -> io::Result<()> {
+        self.slice(&[value])
+    }
+
+    fn vec<T: Copy>(&mut self, data: &[T]) -> io::Result<()> {
+        try!(self.pod(data.len() as u64));
+        self.slice(data)
+    }
+
+    fn indices(&mut self, indices: &Indices) -> io::Result<()> {
+        match *indices {
+            Indices::U16(ref indices) => {
+                try!(self.pod(16u32));
+                self.vec(indices)
+            }
+            Indices::U32(ref indices) => {
+                try!(self.pod(32u32));
+                self.vec(indices)
+            }
+        }
+    }
+
+    fn string(&mut self, s: &str) -> io::Result<()> {
+        self.vec(s.as_bytes())
+    }
+
+    /// Texture paths are stored relative to the source, which may be
+    /// loaded from another working directory next time.
+    fn material(&mut self, material: &Material, dir: &Path) -> io::Result<()> {
+        try!(self.string(&material.name));
+        try!(self.pod([material.ambient, material.diffuse, material.specular]));
+        try!(self.pod([material.shininess, material.dissolve]));
+        try!(self.pod(material.illum));
+        for map in &[&material.ambient_map,
+                     &material.diffuse_map,
+                     &material.specular_map,
+                     &material.shininess_map,
+                     &material.dissolve_map,
+                     &material.bump_map] {
+            match **map {
+                None => try!(self.pod(0u32)),
+                Some(TextureMap::File(ref path)) => {
+                    try!(self.pod(1u32));
+                    try!(self.string(&path.strip_prefix(dir).unwrap_or(path).to_string_lossy()));
+                }
+                Some(TextureMap::Embedded(ref data)) => {
+                    try!(self.pod(2u32));
+                    try!(self.vec(data));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads what `Writer` wrote, checking every length against the size of
+/// the file before allocating anything.
+struct Reader<R: Read> {
+    input: R,
+    offset: usize,
+    len: u64,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads plain data written by `Writer::slice`, and the zeros after it.
+    fn read_slice<T: Copy>(&mut self, data: &mut [T]) -> Option<()> {
+        let len = data.len() * mem::size_of::<T>();
+        let padding = (ALIGN - (self.offset + len) % ALIGN) % ALIGN;
+        if (self.offset + len + padding) as u64 > self.len {
+            return None;
+        }
+        let bytes = unsafe { slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, len) };
+        try_opt!(self.input.read_exact(bytes).ok());
+        let mut zeros = [0; ALIGN];
+        try_opt!(self.input.read_exact(&mut zeros[..padding]).ok());
+        self.offset += len + padding;
+        if zeros.iter().any(|&b| b != 0) {
+            return None;
+        }
+        Some(())
+    }
+
+    fn pod<T: Copy>(&mut self) -> Option<T> {
+        let mut value = [unsafe { mem::zeroed::<T>() }];
+        try_opt!(self.read_slice(&mut value));
+        Some(value[0])
+    }
+
+    fn vec<T: Copy>(&mut self) -> Option<Vec<T>> {
+        let len = try_opt!(self.pod::<u64>());
+        if len.saturating_mul(mem::size_of::<T>() as u64) > self.len {
+            return None;
+        }
+        let mut data = vec![unsafe { mem::zeroed::<T>() }; len as usize];
+        try_opt!(self.read_slice(&mut data));
+        Some(data)
+    }
+
+    fn indices(&mut self) -> Option<Indices> {
+        match try_opt!(self.pod::<u32>()) {
+            16 => self.vec().map(Indices::U16),
+            32 => self.vec().map(Indices::U32),
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(try_opt!(self.vec())).ok()
+    }
+
+    fn list<T, F: FnMut(&mut Reader<R>) -> Option<T>>(&mut self, mut item: F) -> Option<Vec<T>> {
+        let len = try_opt!(self.pod::<u64>());
+        let mut list = Vec::new();
+        for _ in 0..len {
+            list.push(try_opt!(item(self)));
+        }
+        Some(list)
+    }
+
+    fn material(&mut self, dir: &Path) -> Option<Material> {
+        let mut material = Material::new(&try_opt!(self.string()));
+        let colors = try_opt!(self.pod::<[Vec3<f32>; 3]>());
+        let factors = try_opt!(self.pod::<[f32; 2]>());
+        material.ambient = colors[0];
+        material.diffuse = colors[1];
+        material.specular = colors[2];
+        material.shininess = factors[0];
+        material.dissolve = factors[1];
+        material.illum = try_opt!(self.pod::<u32>());
+
+        let mut maps = Vec::new();
+        for _ in 0..6 {
+            maps.push(match try_opt!(self.pod::<u32>()) {
+                0 => None,
+                1 => Some(TextureMap::File(dir.join(try_opt!(self.string())))),
+                2 => Some(TextureMap::Embedded(Arc::new(try_opt!(self.vec())))),
+                _ => return None,
+            });
+        }
+        material.bump_map = maps.pop().unwrap();
+        material.dissolve_map = maps.pop().unwrap();
+        material.shininess_map = maps.pop().unwrap();
+        material.specular_map = maps.pop().unwrap();
+        material.diffuse_map = maps.pop().unwrap();
+        material.ambient_map = maps.pop().unwrap();
+        Some(material)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    use na::Vec3;
+
+    use normals::NormalMode;
+    use obj::{self, Obj, LoadOptions};
+    use super::{cache_path, load_cached};
+
+    const SOURCE: &'static str = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n";
+
+    /// Offsets of fields in the header.
+    const VERSION: u64 = 8;
+    const LAYOUT: u64 = 16;
+    const MODIFIED: u64 = 48;
+    const DATA: usize = 72;
+
+    fn read(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn write(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    fn patch(path: &Path, offset: u64, data: &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    /// A fresh directory of its own for every test, with the source in it.
+    fn source(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bunny_cache_{}", test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.obj");
+        write(&path, SOURCE.as_bytes());
+        path
+    }
+
+    /// Loads through the cache, returns the model and whether the source was parsed.
+    fn load(path: &Path, options: &LoadOptions) -> (Obj, bool) {
+        let parsed = Cell::new(false);
+        let obj = load_cached(path, options, |path, options| {
+            parsed.set(true);
+            obj::parse_bytes(&read(path), Path::new(""), options)
+        });
+        (obj.unwrap(), parsed.get())
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = source("round_trip");
+        let options = LoadOptions::default();
+        let (parsed, was_parsed) = load(&path, &options);
+        assert!(was_parsed && cache_path(&path).exists());
+        let (cached, was_parsed) = load(&path, &options);
+        assert!(!was_parsed);
+        assert_eq!(format!("{:?}", cached), format!("{:?}", parsed));
+    }
+
+    #[test]
+    fn header_mismatch() {
+        let path = source("header_mismatch");
+        let cache = cache_path(&path);
+        let options = LoadOptions::default();
+        load(&path, &options);
+
+        for &(offset, value) in &[(VERSION, 1u8), (LAYOUT, 0)] {
+            patch(&cache, offset, &[value]);
+            assert!(load(&path, &options).1);
+            // The cache was written again.
+            assert!(!load(&path, &options).1);
+        }
+
+        let flat = LoadOptions { normals: NormalMode::Flat, ..LoadOptions::default() };
+        assert!(load(&path, &flat).1);
+        assert!(!load(&path, &flat).1);
+        assert!(load(&path, &options).1);
+    }
+
+    #[test]
+    fn source_change() {
+        let path = source("source_change");
+        let cache = cache_path(&path);
+        let options = LoadOptions::default();
+        load(&path, &options);
+
+        // Touched, but not changed: the hash still matches, and the new time is stored.
+        patch(&cache, MODIFIED, &[0; 8]);
+        assert!(!load(&path, &options).1);
+        assert!(read(&cache)[MODIFIED as usize..][..8] != [0; 8]);
+
+        // Changed without changing the length.
+        write(&path, SOURCE.replace("v 1 0 0", "v 2 0 0").as_bytes());
+        patch(&cache, MODIFIED, &[0; 8]);
+        let (obj, was_parsed) = load(&path, &options);
+        assert!(was_parsed);
+        assert_eq!(obj.vertices[1].position, Vec3::new(2.0, 0.0, 0.0));
+
+        write(&path, format!("{}v 0 0 1\n", SOURCE).as_bytes());
+        assert!(load(&path, &options).1);
+        assert!(!load(&path, &options).1);
+    }
+
+    #[test]
+    fn damaged_cache() {
+        let path = source("damaged_cache");
+        let cache = cache_path(&path);
+        let options = LoadOptions::default();
+        load(&path, &options);
+        let data = read(&cache);
+
+        for len in 0..data.len() {
+            write(&cache, &data[..len]);
+            assert!(load(&path, &options).1, "truncated to {} bytes", len);
+        }
+
+        // A byte too many shifts everything after it out of alignment.
+        for &offset in &[DATA, DATA + 4, data.len() - 8] {
+            let mut shifted = data.clone();
+            shifted.insert(offset, 0);
+            write(&cache, &shifted);
+            assert!(load(&path, &options).1, "shifted at {}", offset);
+        }
+        assert!(!load(&path, &options).1);
+    }
+}
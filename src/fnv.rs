@@ -0,0 +1,27 @@
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The 64 bit FNV-1a hash. It is much faster than the default SipHash on
+/// the small keys of the vertex cache, and collision attacks on a model
+/// viewer are not a concern.
+pub struct FnvHasher(u64);
+
+pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
@@ -0,0 +1,955 @@
+//! Reader for glTF 2.0 static meshes, both `.gltf` JSON and binary `.glb`.
+//!
+//! The meshes of the default scene are flattened into a single model with
+//! the node transforms applied, and every mesh instance becomes a group.
+//! Buffers and images come from data URIs, the binary chunk of a `.glb`, or
+//! files relative to the model; nothing is fetched from the network.
+//! Metallic-roughness materials are approximated with the Phong materials
+//! of `mtl`.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+
+use na::{Vec3, Vec4};
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::{self, Json};
+
+use float::f32_from_bits;
+use mtl::{Material, TextureMap};
+use obj::{Group, IndexedMesh, LoadOptions, MaterialRange, Obj, ObjError, Result};
+use transform::{self, determinant, from_trs, multiply, transform_normal, transform_point};
+
+const GLB_MAGIC: &'static [u8] = b"glTF";
+const CHUNK_JSON: u32 = 0x4e4f534a;
+const CHUNK_BIN: u32 = 0x004e4942;
+
+/// Whether the data looks like a `.glb`, or like the JSON of a `.gltf`.
+pub fn is_gltf(data: &[u8]) -> bool {
+    data.starts_with(GLB_MAGIC) || data.iter().find(|&&b| !(b as char).is_whitespace()) == Some(&b'{')
+}
+
+/// Relative buffer and image files are looked up in `dir`.
+pub fn parse_bytes(data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj> {
+    let (json, bin) = if data.starts_with(GLB_MAGIC) {
+        try!(split_glb(data))
+    } else {
+        (data, None)
+    };
+    let text = try!(str::from_utf8(json).map_err(|_| malformed("the JSON is not valid UTF-8")));
+    let root = try!(Json::from_str(text).map_err(|err| match err {
+        json::ParserError::SyntaxError(code, line, column) => {
+            malformed(format!("{} at line {}, column {} of the JSON", json::error_str(code), line, column))
+        }
+        json::ParserError::IoError(err) => ObjError::Io(err),
+    }));
+    let root = Object::new(&root, String::new());
+
+    let asset = try!(try!(root.object("asset")).ok_or_else(|| root.missing("asset")));
+    let version = try!(try!(asset.string("version")).ok_or_else(|| asset.missing("version")));
+    if !version.starts_with("2.") {
+        return Err(malformed(format!("glTF {} is not supported, only 2.0 is", version)));
+    }
+
+    let gltf = try!(Gltf::new(root, dir, bin));
+    let mut mesh = IndexedMesh::default();
+    for i in 0..gltf.count("materials") {
+        let material = try!(gltf.material(i));
+        mesh.materials.push(material);
+    }
+    let nodes = try!(gltf.scene_nodes());
+    if nodes.is_empty() {
+        // Without nodes, show the meshes as they are.
+        for i in 0..gltf.count("meshes") {
+            try!(gltf.add_mesh(i, "", &transform::IDENTITY, &mut mesh));
+        }
+    }
+    for node in nodes {
+        try!(gltf.add_node(node, &transform::IDENTITY, 0, &mut mesh));
+    }
+    mesh.into_obj(options)
+}
+
+fn malformed<S: Into<String>>(message: S) -> ObjError {
+    ObjError::Malformed(message.into())
+}
+
+fn unexpected_eof() -> ObjError {
+    ObjError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "GLB data ends before the last chunk"))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (0..4).fold(0, |bits, i| bits | (bytes[i] as u32) << (8 * i))
+}
+
+/// Splits a `.glb` into the JSON and the optional binary chunk.
+fn split_glb(data: &[u8]) -> Result<(&[u8], Option<&[u8]>)> {
+    if data.len() < 12 {
+        return Err(unexpected_eof());
+    }
+    let version = read_u32(&data[4..]);
+    if version != 2 {
+        return Err(malformed(format!("GLB version {} is not supported, only 2 is", version)));
+    }
+    let length = read_u32(&data[8..]) as usize;
+    if data.len() < length {
+        return Err(unexpected_eof());
+    }
+
+    let mut chunks = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= length {
+        let chunk_length = read_u32(&data[offset..]) as usize;
+        let chunk_type = read_u32(&data[offset + 4..]);
+        let start = offset + 8;
+        if length - start < chunk_length {
+            return Err(unexpected_eof());
+        }
+        chunks.push((chunk_type, &data[start..start + chunk_length]));
+        // Chunks are padded to four bytes.
+        offset = start + (chunk_length + 3) / 4 * 4;
+    }
+    match chunks.get(0) {
+        Some(&(CHUNK_JSON, json)) => {
+            let bin = chunks.get(1).and_then(|&(ty, bin)| if ty == CHUNK_BIN { Some(bin) } else { None });
+            Ok((json, bin))
+        }
+        _ => Err(malformed("the first GLB chunk is not JSON")),
+    }
+}
+
+/// A JSON object, with its path for error messages.
+#[derive(Clone)]
+struct Object<'a> {
+    json: &'a Json,
+    path: String,
+}
+
+impl<'a> Object<'a> {
+    fn new(json: &'a Json, path: String) -> Object<'a> {
+        Object { json: json, path: path }
+    }
+
+    fn field_path(&self, key: &str) -> String {
+        if self.path.is_empty() { key.to_owned() } else { format!("{}.{}", self.path, key) }
+    }
+
+    fn missing(&self, key: &str) -> ObjError {
+        malformed(format!("{} is missing", self.field_path(key)))
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> ObjError {
+        malformed(format!("{} should be {}", self.field_path(key), expected))
+    }
+
+    fn object(&self, key: &str) -> Result<Option<Object<'a>>> {
+        match self.json.find(key) {
+            None => Ok(None),
+            Some(json) if json.is_object() => Ok(Some(Object::new(json, self.field_path(key)))),
+            Some(_) => Err(self.invalid(key, "an object")),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<Option<&'a str>> {
+        match self.json.find(key) {
+            None => Ok(None),
+            Some(json) => json.as_string().map(Some).ok_or_else(|| self.invalid(key, "a string")),
+        }
+    }
+
+    fn index(&self, key: &str) -> Result<Option<usize>> {
+        match self.json.find(key) {
+            None => Ok(None),
+            Some(json) => json.as_u64().map(|i| Some(i as usize)).ok_or_else(|| self.invalid(key, "a non-negative integer")),
+        }
+    }
+
+    fn required_index(&self, key: &str) -> Result<usize> {
+        try!(self.index(key)).ok_or_else(|| self.missing(key))
+    }
+
+    fn indices(&self, key: &str) -> Result<Vec<usize>> {
+        let items = match self.json.find(key) {
+            None => return Ok(Vec::new()),
+            Some(json) => try!(json.as_array().ok_or_else(|| self.invalid(key, "an array"))),
+        };
+        items.iter()
+             .map(|item| item.as_u64().map(|i| i as usize).ok_or_else(|| self.invalid(key, "an array of indices")))
+             .collect()
+    }
+
+    fn number(&self, key: &str, default: f32) -> Result<f32> {
+        match self.json.find(key) {
+            None => Ok(default),
+            Some(json) => json.as_f64().map(|x| x as f32).ok_or_else(|| self.invalid(key, "a number")),
+        }
+    }
+
+    /// Reads an array of exactly `default.len()` numbers.
+    fn numbers(&self, key: &str, default: &[f32]) -> Result<Vec<f32>> {
+        let expected = || self.invalid(key, &format!("an array of {} numbers", default.len()));
+        let items = match self.json.find(key) {
+            None => return Ok(default.to_vec()),
+            Some(json) => try!(json.as_array().ok_or_else(&expected)),
+        };
+        if items.len() != default.len() {
+            return Err(expected());
+        }
+        items.iter().map(|item| item.as_f64().map(|x| x as f32).ok_or_else(&expected)).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ComponentType {
+    I8,
+    U8,
+    I16,
+    U16,
+    U32,
+    F32,
+}
+
+impl ComponentType {
+    fn from_code(code: usize) -> Option<ComponentType> {
+        match code {
+            5120 => Some(ComponentType::I8),
+            5121 => Some(ComponentType::U8),
+            5122 => Some(ComponentType::I16),
+            5123 => Some(ComponentType::U16),
+            5125 => Some(ComponentType::U32),
+            5126 => Some(ComponentType::F32),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ComponentType::I8 | ComponentType::U8 => 1,
+            ComponentType::I16 | ComponentType::U16 => 2,
+            ComponentType::U32 | ComponentType::F32 => 4,
+        }
+    }
+
+    /// Reads a little-endian value.
+    fn read(self, bytes: &[u8]) -> f64 {
+        let bits = (0..self.size()).fold(0u32, |bits, i| bits | (bytes[i] as u32) << (8 * i));
+        match self {
+            ComponentType::I8 => bits as u8 as i8 as f64,
+            ComponentType::U8 => bits as u8 as f64,
+            ComponentType::I16 => bits as u16 as i16 as f64,
+            ComponentType::U16 => bits as u16 as f64,
+            ComponentType::U32 => bits as f64,
+            ComponentType::F32 => f32_from_bits(bits) as f64,
+        }
+    }
+
+    /// Maps a normalized integer onto `0..1`, or `-1..1` if it is signed.
+    fn normalize(self, value: f64) -> f64 {
+        match self {
+            ComponentType::I8 => (value / 127.0).max(-1.0),
+            ComponentType::U8 => value / 255.0,
+            ComponentType::I16 => (value / 32767.0).max(-1.0),
+            ComponentType::U16 => value / 65535.0,
+            ComponentType::U32 => value / 4294967295.0,
+            ComponentType::F32 => value,
+        }
+    }
+}
+
+/// Values of an accessor, `components` per element.
+struct Values {
+    data: Vec<f64>,
+    components: usize,
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        self.data.len() / self.components
+    }
+
+    /// Element `i` as a vector, padded with `pad`.
+    fn vec4(&self, i: usize, pad: Vec4<f32>) -> Vec4<f32> {
+        let e = &self.data[i * self.components..(i + 1) * self.components];
+        let get = |c: usize, default: f32| e.get(c).map_or(default, |&x| x as f32);
+        Vec4::new(get(0, pad.x), get(1, pad.y), get(2, pad.z), get(3, pad.w))
+    }
+
+    fn vec3(&self, i: usize) -> Vec3<f32> {
+        let v = self.vec4(i, Vec4::new(0.0, 0.0, 0.0, 0.0));
+        Vec3::new(v.x, v.y, v.z)
+    }
+}
+
+struct Gltf<'a> {
+    root: Object<'a>,
+    /// Where relative URIs point to.
+    dir: PathBuf,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl<'a> Gltf<'a> {
+    fn new(root: Object<'a>, dir: &Path, bin: Option<&[u8]>) -> Result<Gltf<'a>> {
+        let mut gltf = Gltf {
+            root: root,
+            dir: dir.to_path_buf(),
+            buffers: Vec::new(),
+        };
+        for i in 0..gltf.count("buffers") {
+            let buffer = try!(gltf.item("buffers", i));
+            let length = try!(buffer.required_index("byteLength"));
+            let data = match try!(buffer.string("uri")) {
+                Some(uri) => try!(read_uri(uri, dir, &buffer.path)),
+                None => try!(bin.map(|bin| bin.to_vec()).ok_or_else(|| buffer.missing("uri"))),
+            };
+            if data.len() < length {
+                return Err(malformed(format!("{} has {} bytes, but {} are expected", buffer.path, data.len(), length)));
+            }
+            gltf.buffers.push(data);
+        }
+        Ok(gltf)
+    }
+
+    fn count(&self, array: &str) -> usize {
+        self.root.json.find(array).and_then(Json::as_array).map_or(0, |items| items.len())
+    }
+
+    /// Element `index` of a top level array, like `accessors[2]`.
+    fn item(&self, array: &str, index: usize) -> Result<Object<'a>> {
+        let path = format!("{}[{}]", array, index);
+        match self.root.json.find(array).and_then(Json::as_array).and_then(|items| items.get(index)) {
+            Some(json) if json.is_object() => Ok(Object::new(json, path)),
+            Some(_) => Err(malformed(format!("{} should be an object", path))),
+            None => Err(malformed(format!("{} does not exist", path))),
+        }
+    }
+
+    /// The root nodes of the default scene, or all root nodes if there
+    /// are no scenes.
+    fn scene_nodes(&self) -> Result<Vec<usize>> {
+        if self.count("scenes") > 0 {
+            let scene = try!(self.root.index("scene")).unwrap_or(0);
+            return try!(self.item("scenes", scene)).indices("nodes");
+        }
+        let count = self.count("nodes");
+        let mut is_child = vec![false; count];
+        for i in 0..count {
+            for child in try!(try!(self.item("nodes", i)).indices("children")) {
+                if child < count {
+                    is_child[child] = true;
+                }
+            }
+        }
+        Ok((0..count).filter(|&i| !is_child[i]).collect())
+    }
+
+    fn add_node(&self, index: usize, parent: &[f32; 16], depth: usize, mesh: &mut IndexedMesh) -> Result<()> {
+        let node = try!(self.item("nodes", index));
+        if depth > self.count("nodes") {
+            return Err(malformed(format!("{} is its own ancestor", node.path)));
+        }
+        let local = match node.json.find("matrix") {
+            Some(_) => {
+                let m = try!(node.numbers("matrix", &[0.0; 16]));
+                let mut matrix = [0.0; 16];
+                matrix.clone_from_slice(&m);
+                matrix
+            }
+            None => {
+                from_trs(&try!(node.numbers("translation", &[0.0; 3])),
+                         &try!(node.numbers("rotation", &[0.0, 0.0, 0.0, 1.0])),
+                         &try!(node.numbers("scale", &[1.0; 3])))
+            }
+        };
+        let transform = multiply(parent, &local);
+
+        if let Some(i) = try!(node.index("mesh")) {
+            try!(self.add_mesh(i, try!(node.string("name")).unwrap_or(""), &transform, mesh));
+        }
+        for child in try!(node.indices("children")) {
+            try!(self.add_node(child, &transform, depth + 1, mesh));
+        }
+        Ok(())
+    }
+
+    /// Adds an instance of a mesh, as a group named after the node and the mesh.
+    fn add_mesh(&self, index: usize, node_name: &str, transform: &[f32; 16], mesh: &mut IndexedMesh) -> Result<()> {
+        let gltf_mesh = try!(self.item("meshes", index));
+        let start = mesh.indices.len();
+        let primitives = match gltf_mesh.json.find("primitives").and_then(Json::as_array) {
+            Some(primitives) => primitives,
+            None => return Err(gltf_mesh.missing("primitives")),
+        };
+        for (i, primitive) in primitives.iter().enumerate() {
+            let primitive = Object::new(primitive, format!("{}.primitives[{}]", gltf_mesh.path, i));
+            try!(self.add_primitive(&primitive, transform, mesh));
+        }
+
+        let name = match try!(gltf_mesh.string("name")) {
+            Some(name) => name.to_owned(),
+            None => format!("mesh {}", index),
+        };
+        mesh.groups.push(Group {
+            object: node_name.to_owned(),
+            name: name,
+            range: start..mesh.indices.len(),
+        });
+        Ok(())
+    }
+
+    fn add_primitive(&self, primitive: &Object<'a>, transform: &[f32; 16], mesh: &mut IndexedMesh) -> Result<()> {
+        let attributes = try!(try!(primitive.object("attributes")).ok_or_else(|| primitive.missing("attributes")));
+        let position_accessor = try!(attributes.required_index("POSITION"));
+        let positions = try!(self.accessor(position_accessor, false));
+        let count = positions.len();
+        let attribute = |name: &str, normalize: bool| -> Result<Option<Values>> {
+            let values = match try!(attributes.index(name)) {
+                Some(i) => try!(self.accessor(i, normalize)),
+                None => return Ok(None),
+            };
+            if values.len() != count {
+                return Err(malformed(format!("{} has {} elements, but POSITION has {}",
+                                             attributes.field_path(name), values.len(), count)));
+            }
+            Ok(Some(values))
+        };
+        let normals = try!(attribute("NORMAL", false));
+        let tex_coords = try!(attribute("TEXCOORD_0", true));
+        let colors = try!(attribute("COLOR_0", true));
+
+        let base = mesh.positions.len();
+        let white = Vec4::new(1.0, 1.0, 1.0, 1.0);
+        if colors.is_some() && mesh.colors.is_empty() {
+            mesh.colors = vec![white; base];
+        }
+        for v in 0..count {
+            mesh.positions.push(transform_point(transform, positions.vec3(v)));
+            mesh.normals.push(normals.as_ref().map_or(Vec3::new(0.0, 0.0, 0.0), |n| transform_normal(transform, n.vec3(v))));
+            // glTF puts the origin of the texture at the top.
+            let t = tex_coords.as_ref().map_or(Vec3::new(0.0, 1.0, 0.0), |t| t.vec3(v));
+            mesh.tex_coords.push(Vec3::new(t.x, 1.0 - t.y, 0.0));
+            if colors.is_some() || !mesh.colors.is_empty() {
+                mesh.colors.push(colors.as_ref().map_or(white, |c| c.vec4(v, white)));
+            }
+        }
+
+        let indices = match try!(primitive.index("indices")) {
+            Some(i) => {
+                let values = try!(self.accessor(i, false));
+                let mut indices = Vec::with_capacity(values.data.len());
+                for &index in &values.data {
+                    if index as usize >= count {
+                        return Err(malformed(format!("{} uses vertex {}, but has only {}", primitive.path, index, count)));
+                    }
+                    indices.push((base + index as usize) as u32);
+                }
+                indices
+            }
+            None => (base as u32..(base + count) as u32).collect(),
+        };
+
+        let start = mesh.indices.len();
+        match try!(primitive.index("mode")).unwrap_or(4) {
+            0 => mesh.points.extend_from_slice(&indices),
+            1 => mesh.lines.extend_from_slice(&indices[..indices.len() / 2 * 2]),
+            mode @ 2 | mode @ 3 => {
+                for w in indices.windows(2) {
+                    mesh.lines.extend_from_slice(w);
+                }
+                if mode == 2 && indices.len() > 2 {
+                    mesh.lines.push(indices[indices.len() - 1]);
+                    mesh.lines.push(indices[0]);
+                }
+            }
+            mode @ 4 | mode @ 5 | mode @ 6 => {
+                let triangles = triangle_list(mode, &indices);
+                // A mirroring transform turns the faces inside out.
+                let mirrored = determinant(transform) < 0.0;
+                for t in triangles.chunks(3) {
+                    let t = if mirrored { [t[0], t[2], t[1]] } else { [t[0], t[1], t[2]] };
+                    mesh.add_polygon(&t);
+                }
+            }
+            _ => return Err(primitive.invalid("mode", "a primitive mode from 0 to 6")),
+        }
+        let material = try!(primitive.index("material"));
+        if material.map_or(false, |m| m >= mesh.materials.len()) {
+            return Err(primitive.invalid("material", "an existing material"));
+        }
+        if mesh.indices.len() > start {
+            mesh.material_ranges.push(MaterialRange {
+                material: material,
+                range: start..mesh.indices.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads the values of an accessor. Normalized integers are mapped
+    /// onto `0..1` or `-1..1` if `normalize` is set.
+    fn accessor(&self, index: usize, normalize: bool) -> Result<Values> {
+        let accessor = try!(self.item("accessors", index));
+        let code = try!(accessor.required_index("componentType"));
+        let ty = try!(ComponentType::from_code(code).ok_or_else(|| accessor.invalid("componentType", "a known component type")));
+        let count = try!(accessor.required_index("count"));
+        let components = match try!(accessor.string("type")) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            Some(_) => return Err(accessor.invalid("type", "`SCALAR` or a vector type")),
+            None => return Err(accessor.missing("type")),
+        };
+
+        let mut data = match try!(accessor.index("bufferView")) {
+            Some(view) => {
+                let offset = try!(accessor.index("byteOffset")).unwrap_or(0);
+                try!(self.read_view(view, offset, ty, components, count))
+            }
+            None => vec![0.0; count * components],
+        };
+
+        if let Some(sparse) = try!(accessor.object("sparse")) {
+            let sparse_count = try!(sparse.required_index("count"));
+            let indices = try!(try!(sparse.object("indices")).ok_or_else(|| sparse.missing("indices")));
+            let values = try!(try!(sparse.object("values")).ok_or_else(|| sparse.missing("values")));
+            let index_code = try!(indices.required_index("componentType"));
+            let index_type = try!(ComponentType::from_code(index_code).ok_or_else(|| indices.invalid("componentType", "an integer type")));
+            let targets = try!(self.read_view(try!(indices.required_index("bufferView")),
+                                              try!(indices.index("byteOffset")).unwrap_or(0),
+                                              index_type,
+                                              1,
+                                              sparse_count));
+            let replacements = try!(self.read_view(try!(values.required_index("bufferView")),
+                                                   try!(values.index("byteOffset")).unwrap_or(0),
+                                                   ty,
+                                                   components,
+                                                   sparse_count));
+            for (i, &target) in targets.iter().enumerate() {
+                let target = target as usize;
+                if target >= count {
+                    return Err(malformed(format!("{} replaces element {} of {}", sparse.path, target, count)));
+                }
+                data[target * components..(target + 1) * components]
+                    .clone_from_slice(&replacements[i * components..(i + 1) * components]);
+            }
+        }
+
+        let is_normalized = accessor.json.find("normalized").and_then(Json::as_boolean).unwrap_or(false);
+        if normalize && is_normalized {
+            for x in &mut data {
+                *x = ty.normalize(*x);
+            }
+        }
+        Ok(Values { data: data, components: components })
+    }
+
+    /// Reads `count` elements of `components` values each, starting at
+    /// `offset` within a buffer view.
+    fn read_view(&self, index: usize, offset: usize, ty: ComponentType, components: usize, count: usize) -> Result<Vec<f64>> {
+        let view = try!(self.item("bufferViews", index));
+        let buffer = try!(view.required_index("buffer"));
+        let data = try!(self.buffers.get(buffer).ok_or_else(|| view.invalid("buffer", "an existing buffer")));
+        let view_offset = try!(view.index("byteOffset")).unwrap_or(0);
+        let view_length = try!(view.required_index("byteLength"));
+        if view_offset.checked_add(view_length).map_or(true, |end| end > data.len()) {
+            return Err(malformed(format!("{} ends past its buffer", view.path)));
+        }
+        let data = &data[view_offset..view_offset + view_length];
+
+        let element_size = ty.size() * components;
+        let stride = try!(view.index("byteStride")).unwrap_or(element_size);
+        let end = if count == 0 {
+            Some(offset)
+        } else {
+            stride.checked_mul(count - 1).and_then(|n| n.checked_add(offset + element_size))
+        };
+        if end.map_or(true, |end| end > data.len()) {
+            return Err(malformed(format!("an accessor of {} reads past its end", view.path)));
+        }
+
+        let mut values = Vec::with_capacity(count * components);
+        for i in 0..count {
+            let element = &data[offset + i * stride..];
+            for c in 0..components {
+                values.push(ty.read(&element[c * ty.size()..]));
+            }
+        }
+        Ok(values)
+    }
+
+    /// Approximates a metallic-roughness material: metals reflect the sky
+    /// tinted by the base color, rough surfaces have wide highlights.
+    fn material(&self, index: usize) -> Result<Material> {
+        let gltf_material = try!(self.item("materials", index));
+        let name = match try!(gltf_material.string("name")) {
+            Some(name) => name.to_owned(),
+            None => format!("material {}", index),
+        };
+        let mut material = Material::new(&name);
+        let null = Json::Null;
+        let pbr = match try!(gltf_material.object("pbrMetallicRoughness")) {
+            Some(pbr) => pbr,
+            None => Object::new(&null, String::new()),
+        };
+        let base = try!(pbr.numbers("baseColorFactor", &[1.0; 4]));
+        let metallic = try!(pbr.number("metallicFactor", 1.0)).max(0.0).min(1.0);
+        let roughness = try!(pbr.number("roughnessFactor", 1.0)).max(0.0).min(1.0);
+
+        let base_color = Vec3::new(base[0], base[1], base[2]);
+        let dielectric = Vec3::new(0.04, 0.04, 0.04);
+        material.ambient = Vec3::new(1.0, 1.0, 1.0);
+        material.diffuse = base_color * (1.0 - metallic);
+        material.specular = (dielectric * (1.0 - metallic) + base_color * metallic) * (1.0 - roughness);
+        // The Blinn-Phong exponent matching the roughness.
+        let alpha = roughness * roughness;
+        material.shininess = (2.0 / (alpha * alpha).max(1e-3) - 2.0).max(1.0).min(1000.0);
+        material.dissolve = base[3];
+        material.illum = if metallic > 0.0 { 3 } else { 2 };
+
+        if let Some(texture) = try!(pbr.object("baseColorTexture")) {
+            let map = try!(self.texture(try!(texture.required_index("index"))));
+            if metallic > 0.0 {
+                material.specular_map = map.clone();
+            }
+            material.diffuse_map = map;
+        }
+        Ok(material)
+    }
+
+    /// The image of a texture, `None` if it only has one from an extension.
+    fn texture(&self, index: usize) -> Result<Option<TextureMap>> {
+        let texture = try!(self.item("textures", index));
+        let image = match try!(texture.index("source")) {
+            Some(image) => try!(self.item("images", image)),
+            None => return Ok(None),
+        };
+        if let Some(uri) = try!(image.string("uri")) {
+            if uri.starts_with("data:") {
+                return Ok(Some(TextureMap::Embedded(Arc::new(try!(read_uri(uri, Path::new(""), &image.path))))));
+            }
+            try!(check_relative(uri, &image.path));
+            return Ok(Some(TextureMap::File(self.dir.join(percent_decode(uri)))));
+        }
+        let view = try!(image.required_index("bufferView"));
+        let data = try!(self.read_view(view, 0, ComponentType::U8, 1, try!(self.view_length(view))));
+        Ok(Some(TextureMap::Embedded(Arc::new(data.into_iter().map(|b| b as u8).collect()))))
+    }
+
+    fn view_length(&self, index: usize) -> Result<usize> {
+        try!(self.item("bufferViews", index)).required_index("byteLength")
+    }
+}
+
+/// Reads a data URI, or a file relative to `dir`. `path` names the
+/// referring object in error messages.
+fn read_uri(uri: &str, dir: &Path, path: &str) -> Result<Vec<u8>> {
+    if uri.starts_with("data:") {
+        let comma = try!(uri.find(',').ok_or_else(|| malformed(format!("the data URI of {} has no data", path))));
+        if !uri[..comma].ends_with(";base64") {
+            return Err(malformed(format!("the data URI of {} is not base64", path)));
+        }
+        return uri[comma + 1..]
+                   .from_base64()
+                   .map_err(|err| malformed(format!("the data URI of {} is not valid base64: {}", path, err)));
+    }
+
+    try!(check_relative(uri, path));
+    let file_path = dir.join(percent_decode(uri));
+    let mut data = Vec::new();
+    try!(File::open(&file_path)
+             .and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|err| ObjError::Io(io::Error::new(err.kind(), format!("{}: {}", file_path.display(), err)))));
+    Ok(data)
+}
+
+/// Rejects URIs with a scheme, like `http:` or `file:`, absolute paths and
+/// paths going up with `..`, also once percent-decoded.
+fn check_relative(uri: &str, path: &str) -> Result<()> {
+    let scheme = uri.find(':').map(|colon| &uri[..colon]);
+    let is_letter = |c: char| (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z');
+    let is_scheme = |s: &str| {
+        s.chars().next().map_or(false, |c| is_letter(c)) &&
+        s.chars().all(|c| is_letter(c) || (c >= '0' && c <= '9') || c == '+' || c == '-' || c == '.')
+    };
+    let decoded = percent_decode(uri);
+    let is_absolute = |s: &str| s.starts_with('/') || s.starts_with('\\') || Path::new(s).is_absolute();
+    if scheme.map_or(false, is_scheme) || is_absolute(uri) || is_absolute(&decoded) {
+        return Err(malformed(format!("{} refers to {}, only relative files and data URIs are loaded", path, uri)));
+    }
+    let goes_up = |s: &str| s.split(|c| c == '/' || c == '\\').any(|part| part == "..");
+    if goes_up(uri) || goes_up(&decoded) {
+        return Err(malformed(format!("{} refers to {}, which is outside of the directory of the model", path, uri)));
+    }
+    Ok(())
+}
+
+/// Decodes `%20` and the like.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Turns strips and fans into a list of triangles.
+fn triangle_list(mode: usize, indices: &[u32]) -> Vec<u32> {
+    let count = indices.len().saturating_sub(2);
+    match mode {
+        5 => {
+            (0..count).flat_map(|i| {
+                          // Every other triangle of a strip is wound the other way.
+                          if i % 2 == 0 {
+                              vec![indices[i], indices[i + 1], indices[i + 2]]
+                          } else {
+                              vec![indices[i + 1], indices[i], indices[i + 2]]
+                          }
+                      })
+                      .collect()
+        }
+        6 => (0..count).flat_map(|i| vec![indices[0], indices[i + 1], indices[i + 2]]).collect(),
+        _ => indices[..indices.len() / 3 * 3].to_vec(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::path::Path;
+
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+
+    use obj::{self, LoadOptions, Obj, ObjError};
+    use super::parse_bytes;
+
+    /// The positions of a successful load, or a part of the error message.
+    type Expected = Result<Vec<[f32; 3]>, &'static str>;
+
+    const TRIANGLE: [f32; 9] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+    const VIEW: &'static str = r#"{"buffer": 0, "byteLength": 36}"#;
+    const ACCESSOR: &'static str = r#"{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}"#;
+
+    fn triangle() -> Vec<[f32; 3]> {
+        vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+    }
+
+    fn bytes(values: &[f32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &x in values {
+            let bits = unsafe { mem::transmute::<f32, u32>(x) };
+            data.extend((0..4).map(|i| (bits >> (8 * i)) as u8));
+        }
+        data
+    }
+
+    fn buffer(uri: &str, length: usize) -> String {
+        format!(r#"{{"byteLength": {}, "uri": "{}"}}"#, length, uri)
+    }
+
+    fn data_buffer(data: &[u8]) -> String {
+        buffer(&format!("data:application/octet-stream;base64,{}", data.to_base64(STANDARD)), data.len())
+    }
+
+    /// A mesh of one primitive, with positions from the first of the
+    /// `accessors`. `extra` is appended to the root object.
+    fn document(buffer: &str, views: &str, accessors: &str, extra: &str) -> String {
+        format!(r#"{{"asset": {{"version": "2.0"}}, "buffers": [{}], "bufferViews": [{}], "accessors": [{}],
+                    "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}}}]}}]{}}}"#,
+                buffer,
+                views,
+                accessors,
+                extra)
+    }
+
+    fn load(data: &[u8]) -> obj::Result<Obj> {
+        parse_bytes(data, Path::new(""), &LoadOptions::default())
+    }
+
+    fn positions(obj: &Obj) -> Vec<[f32; 3]> {
+        obj.vertices.iter().map(|v| [v.position.x, v.position.y, v.position.z]).collect()
+    }
+
+    fn indices(obj: &Obj) -> Vec<u32> {
+        (0..obj.indices.len()).map(|i| obj.indices.get(i)).collect()
+    }
+
+    fn check(data: &[u8], expected: &Expected) {
+        match (load(data), expected) {
+            (Ok(ref obj), &Ok(ref expected)) => assert_eq!(&positions(obj), expected),
+            (Err(err), &Err(message)) => assert!(err.to_string().contains(message), "`{}` should mention `{}`", err, message),
+            (result, expected) => panic!("expected {:?}, got {:?}", expected, result.map(|obj| positions(&obj))),
+        }
+    }
+
+    fn glb(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for &(ty, data) in chunks {
+            let length = (data.len() + 3) / 4 * 4;
+            body.extend((0..4).map(|i| (length >> (8 * i)) as u8));
+            body.extend(ty.iter());
+            body.extend(data.iter());
+            body.extend((data.len()..length).map(|_| b' '));
+        }
+        let mut data = b"glTF\x02\0\0\0".to_vec();
+        let length = 12 + body.len();
+        data.extend((0..4).map(|i| (length >> (8 * i)) as u8));
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn glb_chunks() {
+        let json = document(r#"{"byteLength": 36}"#, VIEW, ACCESSOR, "");
+        let (json, bin) = (json.as_bytes(), &bytes(&TRIANGLE)[..]);
+        let valid = glb(&[(b"JSON", json), (b"BIN\0", bin)]);
+        let mut version_one = valid.clone();
+        version_one[4] = 1;
+        let cases: Vec<(Vec<u8>, Expected)> =
+            vec![(valid.clone(), Ok(triangle())),
+                 (version_one, Err("GLB version 1 is not supported")),
+                 (valid[..valid.len() - 4].to_vec(), Err("ends before the last chunk")),
+                 (glb(&[(b"BIN\0", bin), (b"JSON", json)]), Err("the first GLB chunk is not JSON")),
+                 (glb(&[(b"JSON", json)]), Err("buffers[0].uri is missing"))];
+        for &(ref data, ref expected) in &cases {
+            check(data, expected);
+        }
+    }
+
+    #[test]
+    fn accessors() {
+        let plain = bytes(&TRIANGLE);
+        let interleaved = bytes(&[0.0, 0.0, 0.0, 9.0, 1.0, 0.0, 0.0, 9.0, 0.0, 1.0, 0.0]);
+        // The triangle, the sparse indices 1 and 3 as shorts, and a replacement.
+        let mut sparse = plain.clone();
+        sparse.extend(&[1, 0, 3, 0]);
+        sparse.extend(bytes(&[5.0, 6.0, 7.0]));
+        let sparse_views = format!(r#"{}, {{"buffer": 0, "byteOffset": 36, "byteLength": 4}},
+                                       {{"buffer": 0, "byteOffset": 40, "byteLength": 12}}"#,
+                                   VIEW);
+        let sparse_accessor = |view: &str, index_offset: usize| {
+            format!(r#"{{{} "componentType": 5126, "count": 3, "type": "VEC3",
+                        "sparse": {{"count": 1, "values": {{"bufferView": 2}},
+                                   "indices": {{"bufferView": 1, "byteOffset": {}, "componentType": 5123}}}}}}"#,
+                    view,
+                    index_offset)
+        };
+        let strided = r#"{"buffer": 0, "byteLength": 44, "byteStride": 16}"#;
+
+        let cases: Vec<(&[u8], String, String, Expected)> =
+            vec![(&plain, VIEW.to_owned(), ACCESSOR.to_owned(), Ok(triangle())),
+                 (&interleaved, strided.to_owned(), ACCESSOR.to_owned(), Ok(triangle())),
+                 (&plain,
+                  VIEW.to_owned(),
+                  ACCESSOR.replace(r#""count": 3"#, r#""count": 2, "byteOffset": 12"#),
+                  Ok(triangle()[1..].to_vec())),
+                 (&plain, VIEW.to_owned(), ACCESSOR.replace('3', "4"), Err("bufferViews[0] reads past its end")),
+                 (&plain,
+                  VIEW.to_owned(),
+                  ACCESSOR.replace(r#""count""#, r#""byteOffset": 4, "count""#),
+                  Err("reads past its end")),
+                 (&plain, strided.replace("44", "36"), ACCESSOR.to_owned(), Err("reads past its end")),
+                 (&sparse,
+                  sparse_views.clone(),
+                  sparse_accessor(r#""bufferView": 0,"#, 0),
+                  Ok(vec![[0.0, 0.0, 0.0], [5.0, 6.0, 7.0], [0.0, 1.0, 0.0]])),
+                 // Without a view, the values start out as zeros.
+                 (&sparse,
+                  sparse_views.clone(),
+                  sparse_accessor("", 0),
+                  Ok(vec![[0.0, 0.0, 0.0], [5.0, 6.0, 7.0], [0.0, 0.0, 0.0]])),
+                 (&sparse, sparse_views.clone(), sparse_accessor("", 2), Err("replaces element 3 of 3"))];
+        for &(data, ref views, ref accessors, ref expected) in &cases {
+            check(document(&data_buffer(data), views, accessors, "").as_bytes(), expected);
+        }
+    }
+
+    #[test]
+    fn buffer_views() {
+        let data = data_buffer(&bytes(&TRIANGLE));
+        let cases: Vec<(&str, &str, Expected)> =
+            vec![(r#"{"buffer": 0, "byteOffset": 4, "byteLength": 36}"#, ACCESSOR, Err("bufferViews[0] ends past its buffer")),
+                 (r#"{"buffer": 0, "byteLength": 40}"#, ACCESSOR, Err("bufferViews[0] ends past its buffer")),
+                 (r#"{"buffer": 1, "byteLength": 36}"#, ACCESSOR, Err("bufferViews[0].buffer should be an existing buffer")),
+                 (VIEW, r#"{"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3"}"#,
+                  Err("bufferViews[1] does not exist"))];
+        for &(view, accessor, ref expected) in &cases {
+            check(document(&data, view, accessor, "").as_bytes(), expected);
+        }
+    }
+
+    #[test]
+    fn node_transforms() {
+        let data = data_buffer(&bytes(&TRIANGLE));
+        let translated = vec![[1.0, 2.0, 3.0], [2.0, 2.0, 3.0], [1.0, 3.0, 3.0]];
+        let mirrored = vec![[0.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let cases = vec![(r#"{"mesh": 0, "translation": [1, 2, 3]}"#, translated.clone(), false),
+                         (r#"{"mesh": 0, "matrix": [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 1, 2, 3, 1]}"#, translated, false),
+                         // The child is scaled first, then moved with its parent.
+                         (r#"{"children": [1], "translation": [1, 0, 0]}, {"mesh": 0, "scale": [2, 2, 2]}"#,
+                          vec![[1.0, 0.0, 0.0], [3.0, 0.0, 0.0], [1.0, 2.0, 0.0]],
+                          false),
+                         (r#"{"mesh": 0, "scale": [-1, 1, 1]}"#, mirrored.clone(), true),
+                         (r#"{"mesh": 0, "matrix": [-1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1]}"#, mirrored, true)];
+        for &(nodes, ref expected, is_mirrored) in &cases {
+            let text = document(&data, VIEW, ACCESSOR, &format!(r#", "nodes": [{}]"#, nodes));
+            let obj = load(text.as_bytes()).unwrap();
+            assert_eq!(&positions(&obj), expected);
+            // Mirrored faces are turned back around, so that they still face the front.
+            let expected_indices = if is_mirrored { [0, 2, 1] } else { [0, 1, 2] };
+            assert_eq!(indices(&obj), expected_indices);
+            assert_eq!(obj.normals[0].normal.z, 1.0);
+        }
+    }
+
+    #[test]
+    fn uris() {
+        let plain = bytes(&TRIANGLE);
+        let base64 = plain.to_base64(STANDARD);
+        let cases: Vec<(String, Expected)> =
+            vec![(format!("data:application/octet-stream;base64,{}", base64), Ok(triangle())),
+                 (format!("data:;base64,{}", base64), Ok(triangle())),
+                 (format!("data:application/octet-stream,{}", base64), Err("is not base64")),
+                 ("data:application/octet-stream;base64".to_owned(), Err("has no data")),
+                 ("data:application/octet-stream;base64,!!!!".to_owned(), Err("is not valid base64")),
+                 ("http://example.com/triangle.bin".to_owned(), Err("only relative files and data URIs")),
+                 ("file:///etc/passwd".to_owned(), Err("only relative files and data URIs")),
+                 ("/etc/passwd".to_owned(), Err("only relative files and data URIs")),
+                 ("%2Fetc%2Fpasswd".to_owned(), Err("only relative files and data URIs")),
+                 ("../../etc/passwd".to_owned(), Err("outside of the directory of the model")),
+                 ("..%2F..%2Fsecret".to_owned(), Err("outside of the directory of the model")),
+                 ("sub/../../x".to_owned(), Err("outside of the directory of the model")),
+                 // Escaped for JSON.
+                 ("sub\\\\..\\\\x".to_owned(), Err("outside of the directory of the model")),
+                 ("sub/%2e%2e/%2E%2E/x".to_owned(), Err("outside of the directory of the model"))];
+        for &(ref uri, ref expected) in &cases {
+            check(document(&buffer(uri, 36), VIEW, ACCESSOR, "").as_bytes(), expected);
+        }
+
+        // Names which merely contain dots are looked up.
+        for uri in &["missing..bin", "sub/..missing/x.bin"] {
+            match load(document(&buffer(uri, 36), VIEW, ACCESSOR, "").as_bytes()) {
+                Err(ObjError::Io(_)) => (),
+                other => panic!("expected {} to be looked up, got {:?}", uri, other.map(|obj| positions(&obj))),
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Loading, saving and simplifying of the models shown by the viewer:
+//! OBJ, PLY, STL, glTF, OFF and XYZ point clouds.
+//!
+//! `Registry` picks the reader of a file by its extension or its first
+//! bytes, and can be extended with other formats through
+//! `loader::MeshLoader`.
+
+#[macro_use]
+extern crate glium;
+extern crate nalgebra as na;
+extern crate flate2;
+extern crate memmap;
+extern crate crossbeam;
+extern crate rustc_serialize;
+
+pub mod obj;
+pub mod ply;
+pub mod stl;
+pub mod gltf;
+pub mod off;
+pub mod xyz;
+pub mod loader;
+pub mod bounds;
+pub mod transform;
+pub mod simplify;
+pub mod cache;
+pub mod mtl;
+pub mod normals;
+mod float;
+mod fnv;
+mod triangulate;
+
+pub use loader::{MeshLoader, Registry};
@@ -0,0 +1,443 @@
+//! Picks the reader of a model by the extension of its file or, failing
+//! that, by the first bytes of its data.
+//!
+//! Gzip compressed data is decompressed first, so `model.stl.gz` is read
+//! as STL. Other formats can be added by implementing `MeshLoader` and
+//! registering it, loaders registered later take precedence.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use memmap::{Mmap, Protection};
+
+use gltf;
+use obj::{self, LoadOptions, Obj, ObjError, Result};
+use off;
+use ply;
+use stl;
+use xyz;
+
+/// How many bytes of a file are read to detect its format.
+const HEAD_SIZE: u64 = 4096;
+
+const GZIP_MAGIC: &'static [u8] = &[0x1f, 0x8b];
+
+// Extensions of the built-in formats, as constants since borrowed
+// literals are only `'static` from Rust 1.21 on.
+const OBJ_EXTENSIONS: &'static [&'static str] = &["obj"];
+const PLY_EXTENSIONS: &'static [&'static str] = &["ply"];
+const STL_EXTENSIONS: &'static [&'static str] = &["stl"];
+const GLTF_EXTENSIONS: &'static [&'static str] = &["gltf", "glb"];
+const OFF_EXTENSIONS: &'static [&'static str] = &["off"];
+const XYZ_EXTENSIONS: &'static [&'static str] = &["xyz", "pts"];
+
+pub trait MeshLoader: Send + Sync {
+    /// Name of the format, for messages.
+    fn name(&self) -> &str;
+
+    /// Lowercase extensions of the files, without the dot.
+    fn extensions(&self) -> &[&str];
+
+    /// Whether data starting with `head` is in this format, `len` is the
+    /// size of the whole data when it is known. Formats without a signature
+    /// detect nothing.
+    fn detect(&self, _head: &[u8], _len: Option<u64>) -> bool {
+        false
+    }
+
+    /// Parses a whole model, `dir` is where the files it refers to are.
+    fn parse_bytes(&self, data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj>;
+
+    /// Parses a model from a stream, like decompressed data or the standard
+    /// input. By default the whole stream is read into memory first.
+    fn parse_reader(&self, reader: &mut BufRead, dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        let mut data = Vec::new();
+        try!(reader.read_to_end(&mut data));
+        self.parse_bytes(&data, dir, options)
+    }
+
+    /// Loads an uncompressed file, which is memory-mapped by default.
+    fn load_from_file(&self, path: &Path, options: &LoadOptions) -> Result<Obj> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        // Empty files can't be mapped.
+        if try!(fs::metadata(path)).len() == 0 {
+            return self.parse_bytes(&[], dir, options);
+        }
+        let map = try!(Mmap::open_path(path, Protection::Read));
+        self.parse_bytes(unsafe { map.as_slice() }, dir, options)
+    }
+}
+
+/// A loader made of plain functions, like those of the built-in formats.
+pub struct FnLoader {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    pub detect: fn(&[u8], Option<u64>) -> bool,
+    pub parse_bytes: fn(&[u8], &Path, &LoadOptions) -> Result<Obj>,
+}
+
+impl MeshLoader for FnLoader {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn extensions(&self) -> &[&str] {
+        self.extensions
+    }
+
+    fn detect(&self, head: &[u8], len: Option<u64>) -> bool {
+        (self.detect)(head, len)
+    }
+
+    fn parse_bytes(&self, data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        (self.parse_bytes)(data, dir, options)
+    }
+}
+
+/// OBJ, which is parsed line by line when streamed rather than read into
+/// memory first.
+struct ObjLoader;
+
+impl MeshLoader for ObjLoader {
+    fn name(&self) -> &str {
+        "OBJ"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        OBJ_EXTENSIONS
+    }
+
+    fn parse_bytes(&self, data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        obj::parse_bytes(data, dir, options)
+    }
+
+    fn parse_reader(&self, reader: &mut BufRead, dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        obj::parse_reader(reader, dir, options)
+    }
+}
+
+/// The loaders to pick from. Models which match no extension and no
+/// signature are read by the first loader registered.
+pub struct Registry {
+    loaders: Vec<Box<MeshLoader>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry { loaders: Vec::new() }
+    }
+
+    /// OBJ, which is also the fallback, PLY, STL, glTF, OFF and XYZ.
+    pub fn with_builtin_formats() -> Registry {
+        // Closures only coerce to function pointers since Rust 1.19.
+        fn never(_: &[u8], _: Option<u64>) -> bool {
+            false
+        }
+        fn is_ply(head: &[u8], _: Option<u64>) -> bool {
+            ply::is_ply(head)
+        }
+        fn is_gltf(head: &[u8], _: Option<u64>) -> bool {
+            gltf::is_gltf(head)
+        }
+        fn is_off(head: &[u8], _: Option<u64>) -> bool {
+            off::is_off(head)
+        }
+        fn parse_ply(data: &[u8], _: &Path, options: &LoadOptions) -> Result<Obj> {
+            ply::parse_bytes(data, options)
+        }
+        fn parse_stl(data: &[u8], _: &Path, options: &LoadOptions) -> Result<Obj> {
+            stl::parse_bytes(data, options)
+        }
+        fn parse_off(data: &[u8], _: &Path, options: &LoadOptions) -> Result<Obj> {
+            off::parse_bytes(data, options)
+        }
+        fn parse_xyz(data: &[u8], _: &Path, options: &LoadOptions) -> Result<Obj> {
+            xyz::parse_bytes(data, options)
+        }
+
+        let mut registry = Registry::new();
+        registry.register(Box::new(ObjLoader));
+        registry.register(Box::new(FnLoader {
+            name: "PLY",
+            extensions: PLY_EXTENSIONS,
+            detect: is_ply,
+            parse_bytes: parse_ply,
+        }));
+        registry.register(Box::new(FnLoader {
+            name: "STL",
+            extensions: STL_EXTENSIONS,
+            detect: stl::is_stl,
+            parse_bytes: parse_stl,
+        }));
+        registry.register(Box::new(FnLoader {
+            name: "glTF",
+            extensions: GLTF_EXTENSIONS,
+            detect: is_gltf,
+            parse_bytes: gltf::parse_bytes,
+        }));
+        registry.register(Box::new(FnLoader {
+            name: "OFF",
+            extensions: OFF_EXTENSIONS,
+            detect: is_off,
+            parse_bytes: parse_off,
+        }));
+        registry.register(Box::new(FnLoader {
+            name: "XYZ",
+            extensions: XYZ_EXTENSIONS,
+            detect: never,
+            parse_bytes: parse_xyz,
+        }));
+        registry
+    }
+
+    pub fn register(&mut self, loader: Box<MeshLoader>) {
+        self.loaders.push(loader);
+    }
+
+    /// The loader for a file with the given name, or for data starting
+    /// with `head` of `len` bytes in all.
+    pub fn find(&self, path: Option<&Path>, head: &[u8], len: Option<u64>) -> Option<&MeshLoader> {
+        self.find_known(path, head, len).or_else(|| self.loaders.first().map(|loader| &**loader))
+    }
+
+    /// Like `find`, without falling back to the first loader.
+    fn find_known(&self, path: Option<&Path>, head: &[u8], len: Option<u64>) -> Option<&MeshLoader> {
+        let extension = path.and_then(|path| path.extension())
+                            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let by_extension = extension.and_then(|ext| {
+            self.loaders.iter().rev().find(|loader| loader.extensions().iter().any(|&e| e == ext))
+        });
+        by_extension.or_else(|| self.loaders.iter().rev().find(|loader| loader.detect(head, len)))
+                    .map(|loader| &**loader)
+    }
+
+    pub fn load_from_file(&self, path: &Path, options: &LoadOptions) -> Result<Obj> {
+        let file = try!(File::open(path));
+        let len = try!(file.metadata()).len();
+        let mut head = Vec::new();
+        try!(file.take(HEAD_SIZE).read_to_end(&mut head));
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let is_gz = path.extension().map_or(false, |ext| ext.to_string_lossy().to_lowercase() == "gz");
+        if is_gz || head.starts_with(GZIP_MAGIC) {
+            let file = BufReader::new(try!(File::open(path)));
+            let mut reader = BufReader::new(try!(GzDecoder::new(file)));
+            // The name without `.gz` tells the format of the contents.
+            let inner = if is_gz { path.file_stem().map(Path::new) } else { Some(path) };
+            return self.parse_buffered(&mut reader, inner, dir, options);
+        }
+        self.with_loader(Some(path), &head, Some(len), |loader| loader.load_from_file(path, options))
+    }
+
+    /// Reads a model of unknown format, like one piped to the standard input.
+    pub fn parse_reader<R: BufRead>(&self, mut reader: R, dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        if try!(reader.fill_buf()).starts_with(GZIP_MAGIC) {
+            let mut decompressed = BufReader::new(try!(GzDecoder::new(reader)));
+            return self.parse_buffered(&mut decompressed, None, dir, options);
+        }
+        self.parse_buffered(&mut reader, None, dir, options)
+    }
+
+    /// Detects the format by the start of what is buffered, which may be
+    /// less than `HEAD_SIZE`. The size of streams is unknown.
+    fn parse_buffered(&self, reader: &mut BufRead, path: Option<&Path>, dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        let head = try!(reader.fill_buf()).to_vec();
+        self.with_loader(path, &head, None, |loader| loader.parse_reader(reader, dir, options))
+    }
+
+    /// Parses with the loader for `path` or `head`. When neither tells the
+    /// format the first loader is tried, and its errors say so.
+    fn with_loader<F>(&self, path: Option<&Path>, head: &[u8], len: Option<u64>, parse: F) -> Result<Obj>
+        where F: FnOnce(&MeshLoader) -> Result<Obj>
+    {
+        if let Some(loader) = self.find_known(path, head, len) {
+            return parse(loader);
+        }
+        let loader = try!(self.loaders.first().ok_or_else(|| {
+            ObjError::from(io::Error::new(io::ErrorKind::InvalidInput, "no mesh loaders are registered"))
+        }));
+        parse(&**loader).map_err(|err| {
+            ObjError::Malformed(format!("the format is unknown, and reading it as {} failed: {}", loader.name(), err))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use obj::{LoadOptions, Obj, ObjError, Result};
+    use super::{MeshLoader, Registry};
+
+    const STL: &'static str = "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\n\
+                               vertex 0 1 0\nendloop\nendfacet\nendsolid t\n";
+    const PLY: &'static str = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                               property float z\nelement face 1\nproperty list uchar int vertex_indices\n\
+                               end_header\n0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n";
+
+    /// Fails every parse with its name, to tell which loader was picked.
+    struct Fake {
+        name: &'static str,
+        extensions: [&'static str; 1],
+        signature: &'static [u8],
+    }
+
+    impl MeshLoader for Fake {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &self.extensions
+        }
+
+        fn detect(&self, head: &[u8], _: Option<u64>) -> bool {
+            !self.signature.is_empty() && head.starts_with(self.signature)
+        }
+
+        fn parse_bytes(&self, _: &[u8], _: &Path, _: &LoadOptions) -> Result<Obj> {
+            Err(ObjError::Malformed(self.name.to_owned()))
+        }
+    }
+
+    fn registry(fakes: Vec<Fake>) -> Registry {
+        let mut registry = Registry::new();
+        for fake in fakes {
+            registry.register(Box::new(fake));
+        }
+        registry
+    }
+
+    /// A fresh directory of its own for every test.
+    fn dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bunny_loader_{}", test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The message of the fake loader which read the file.
+    fn read_by(registry: &Registry, path: &Path) -> String {
+        match registry.load_from_file(path, &LoadOptions::default()) {
+            Err(ObjError::Malformed(message)) => message,
+            other => panic!("expected a fake loader to read {:?}, got {:?}", path, other),
+        }
+    }
+
+    fn load(path: &Path) -> Obj {
+        Registry::with_builtin_formats().load_from_file(path, &LoadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn extension_over_signature() {
+        let registry = registry(vec![Fake { name: "a", extensions: ["a"], signature: b"A!" },
+                                     Fake { name: "b", extensions: ["b"], signature: b"B!" }]);
+        let dir = dir("extension_over_signature");
+        for &(name, expected) in &[("model.a", "a"), ("MODEL.A", "a"), ("model.b", "b"), ("model.c", "b"), ("model", "b")] {
+            let path = dir.join(name);
+            write(&path, b"B! data");
+            let read_by = read_by(&registry, &path);
+            assert!(read_by == expected, "{} was read by {}", name, read_by);
+        }
+
+        // The signatures of the built-in formats.
+        let path = dir.join("model");
+        write(&path, STL.as_bytes());
+        assert_eq!(load(&path).vertices.len(), 3);
+        write(&path, PLY.as_bytes());
+        assert_eq!(load(&path).vertices.len(), 3);
+    }
+
+    #[test]
+    fn later_registration_wins() {
+        let registry = registry(vec![Fake { name: "first", extensions: ["a"], signature: b"!" },
+                                     Fake { name: "second", extensions: ["a"], signature: b"!" }]);
+        let dir = dir("later_registration_wins");
+        for name in &["model.a", "model.c"] {
+            let path = dir.join(name);
+            write(&path, b"! data");
+            assert_eq!(read_by(&registry, &path), "second");
+        }
+    }
+
+    #[test]
+    fn compressed() {
+        let dir = dir("compressed");
+        // The extension under `.gz` tells the format.
+        let path = dir.join("model.stl.gz");
+        write(&path, &gzip(STL.as_bytes()));
+        assert_eq!(load(&path).vertices.len(), 3);
+
+        // Gzip data is recognized without `.gz` too, and the contents by
+        // their signature.
+        let path = dir.join("model.bin");
+        write(&path, &gzip(PLY.as_bytes()));
+        assert_eq!(load(&path).indices.len(), 3);
+
+        let obj = Registry::with_builtin_formats()
+                      .parse_reader(&gzip(STL.as_bytes())[..], Path::new(""), &LoadOptions::default())
+                      .unwrap();
+        assert_eq!(obj.vertices.len(), 3);
+    }
+
+    #[test]
+    fn fallback() {
+        let registry = registry(vec![Fake { name: "first", extensions: ["a"], signature: b"A!" },
+                                     Fake { name: "second", extensions: ["b"], signature: b"B!" }]);
+        let path = dir("fallback").join("model.c");
+        write(&path, b"data");
+        assert_eq!(read_by(&registry, &path), "the format is unknown, and reading it as first failed: Malformed model: first");
+        assert_eq!(registry.find(Some(&path), b"data", None).unwrap().name(), "first");
+
+        // Errors of a loader picked by extension are not wrapped.
+        let path = path.with_extension("b");
+        write(&path, b"data");
+        assert_eq!(read_by(&registry, &path), "second");
+    }
+
+    #[test]
+    fn empty_registry() {
+        let registry = Registry::new();
+        assert!(registry.find(None, b"data", None).is_none());
+        let path = dir("empty_registry").join("model.obj");
+        write(&path, b"v 0 0 0\n");
+        match registry.load_from_file(&path, &LoadOptions::default()) {
+            Err(ObjError::Io(ref err)) if err.kind() == io::ErrorKind::InvalidInput => (),
+            other => panic!("expected no loader, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_files() {
+        let dir = dir("empty_files");
+        for name in &["model.obj", "model"] {
+            let path = dir.join(name);
+            write(&path, b"");
+            assert_eq!(load(&path).vertices.len(), 0);
+        }
+        let path = dir.join("model.a");
+        write(&path, b"");
+        let registry = registry(vec![Fake { name: "a", extensions: ["a"], signature: b"" }]);
+        assert_eq!(read_by(&registry, &path), "a");
+
+        let obj = Registry::with_builtin_formats().parse_reader(&b""[..], Path::new(""), &LoadOptions::default());
+        assert_eq!(obj.unwrap().vertices.len(), 0);
+    }
+}
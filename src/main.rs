@@ -3,25 +3,36 @@ extern crate glium;
 extern crate nalgebra as na;
 extern crate num;
 extern crate image;
+extern crate bunny;
 
 use std::env;
+use std::mem;
 use std::process;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::ops::Range;
+use std::path::Path;
 
 use glium::{glutin, DisplayBuild, Surface, VertexBuffer, IndexBuffer, DrawParameters, GlObject};
+use glium::index::{IndexBufferAny, IndicesSource, PrimitiveType};
 use glium::texture::cubemap::Cubemap;
-use glium::texture::RawImage2d;
+use glium::texture::{RawImage2d, Texture2d};
 use glium::backend::glutin_backend::GlutinFacade as Display;
-use glium::glutin::{Event, ElementState, MouseButton};
+use glium::glutin::{Event, ElementState, MouseButton, VirtualKeyCode};
 use glium::glutin::{Window};
 
-use na::{PerspMat3, Iso3, Pnt3, Vec3, BaseFloat, Mat4, UnitQuat, Rotation, FromHomogeneous};
+use na::{PerspMat3, Iso3, Pnt3, Vec3, Mat4, UnitQuat, Rotation, FromHomogeneous};
 
 use num::One;
 
-mod obj;
+use bunny::{bounds, cache, loader, mtl, normals, obj, simplify, stl, transform};
+
 mod gl;
 
+const FIELD_OF_VIEW: f32 = std::f32::consts::FRAC_PI_4;
+
+/// Direction from the model to the camera, as first set up for the bunny.
+const FRAMING_DIRECTION: Vec3<f32> = Vec3 { x: -0.03, y: -0.1, z: 0.4 };
+
 #[cfg(feature = "dyn_assets")]
 macro_rules! load_asset {
     ($x:expr) => (read_asset(concat!("./src/", $x)));
@@ -113,23 +124,140 @@ fn load_skybox_images() -> Vec<RawImage2d<'static, u8>> {
     result
 }
 
+/// Uniforms and textures of an `mtl::Material`, ready for drawing.
+struct SceneMaterial {
+    ambient: Vec3<f32>,
+    diffuse: Vec3<f32>,
+    specular: Vec3<f32>,
+    shininess: f32,
+    dissolve: f32,
+    illum: i32,
+    diffuse_map: Option<Texture2d>,
+    specular_map: Option<Texture2d>,
+}
+
+impl SceneMaterial {
+    fn new(display: &Display, material: &mtl::Material) -> SceneMaterial {
+        let load = |map: &Option<mtl::TextureMap>| map.as_ref().and_then(|map| load_texture(display, map));
+        SceneMaterial {
+            ambient: material.ambient,
+            diffuse: material.diffuse,
+            specular: material.specular,
+            shininess: material.shininess,
+            dissolve: material.dissolve,
+            illum: material.illum as i32,
+            diffuse_map: load(&material.diffuse_map),
+            specular_map: load(&material.specular_map),
+        }
+    }
+}
+
+fn load_texture(display: &Display, map: &mtl::TextureMap) -> Option<Texture2d> {
+    let image = match *map {
+        mtl::TextureMap::File(ref path) => image::open(path),
+        mtl::TextureMap::Embedded(ref data) => image::load_from_memory(data),
+    };
+    match image {
+        Ok(im) => {
+            let im = im.to_rgba();
+            let dimensions = im.dimensions();
+            let im = RawImage2d::from_raw_rgba_reversed(im.into_raw(), dimensions);
+            Some(Texture2d::new(display, im).unwrap())
+        }
+        Err(err) => {
+            match *map {
+                mtl::TextureMap::File(ref path) => println!("Failed to load texture {}: {}", path.display(), err),
+                mtl::TextureMap::Embedded(_) => println!("Failed to load an embedded texture: {}", err),
+            }
+            None
+        }
+    }
+}
+
+/// Part of the model drawn with a single material.
+struct ModelPart {
+    indices: IndexBufferAny,
+    /// Where the indices are in `Obj::indices`.
+    range: Range<usize>,
+    material: Option<usize>,
+    group: Option<usize>,
+}
+
+/// Splits the model into runs of indices which share both
+/// the material and the group.
+fn model_parts(display: &Display, model: &obj::Obj) -> Vec<ModelPart> {
+    let mut bounds = model.material_ranges.iter()
+                                          .map(|r| &r.range)
+                                          .chain(model.groups.iter().map(|g| &g.range))
+                                          .flat_map(|r| vec![r.start, r.end])
+                                          .collect::<Vec<_>>();
+    bounds.sort();
+    bounds.dedup();
+
+    bounds.windows(2).map(|w| {
+        let range = w[0]..w[1];
+        let contains = |r: &Range<usize>| r.start <= range.start && range.end <= r.end;
+        ModelPart {
+            indices: index_buffer(display,
+                                  PrimitiveType::TrianglesList,
+                                  &model.indices.slice(range.clone())),
+            range: range.clone(),
+            material: model.material_ranges.iter()
+                                           .find(|r| contains(&r.range))
+                                           .and_then(|r| r.material),
+            group: model.groups.iter().position(|g| contains(&g.range)),
+        }
+    }).collect()
+}
+
 struct Scene {
     light: Pnt3<f32>,
     camera_position: Pnt3<f32>,
+    /// The point the camera looks at and the model rotates around.
+    model_center: Vec3<f32>,
+    camera_distance: f32,
+    projection: PerspMat3<f32>,
+    model: obj::Obj,
+    /// The model as it was before simplifying it.
+    original_model: Option<obj::Obj>,
+    /// How normals are generated for simplified models.
+    normals: normals::NormalMode,
     display: Display,
     draw_parameters: DrawParameters<'static>,
     model_points: VertexBuffer<obj::Vertex>,
     model_normals: VertexBuffer<obj::Normal>,
-    model_indices: IndexBuffer<u16>,
+    model_tex_coords: VertexBuffer<obj::TexCoord>,
+    model_colors: VertexBuffer<obj::Color>,
+    has_vertex_colors: bool,
+    model_parts: Vec<ModelPart>,
+    model_edges: IndexBufferAny,
+    model_materials: Vec<SceneMaterial>,
+    default_material: SceneMaterial,
+    white_texture: Texture2d,
+    wireframe: bool,
+    group_names: Vec<String>,
+    hidden_groups: Vec<bool>,
+    selected_group: Option<usize>,
+    isolate_selected: bool,
     model_program: glium::Program,
+    model_lines: Option<IndexBufferAny>,
+    model_point_set: Option<IndexBufferAny>,
+    overlay_program: glium::Program,
+    overlay_parameters: DrawParameters<'static>,
+    /// Models without faces are point clouds, their points are drawn as
+    /// sprites instead of overlays.
+    is_point_cloud: bool,
+    points_program: glium::Program,
+    points_parameters: DrawParameters<'static>,
     skybox_points: VertexBuffer<obj::Vertex>,
     skybox_indices: glium::index::NoIndices,
     skybox_texture: Cubemap,
     skybox_program: glium::Program,
+    skybox_parameters: DrawParameters<'static>,
 }
 
 impl Scene {
-    fn new(model: obj::Obj) -> Scene {
+    fn new(model: obj::Obj, normals: normals::NormalMode) -> Scene {
         let display = build_display();
 
         let model_program = glium::Program::from_source(
@@ -139,6 +267,20 @@ impl Scene {
             None,
         ).unwrap();
 
+        let overlay_program = glium::Program::from_source(
+            &display,
+            &load_asset_str!("./shaders/overlay/vertex.glsl"),
+            &load_asset_str!("./shaders/overlay/fragment.glsl"),
+            None,
+        ).unwrap();
+
+        let points_program = glium::Program::from_source(
+            &display,
+            &load_asset_str!("./shaders/points/vertex.glsl"),
+            &load_asset_str!("./shaders/points/fragment.glsl"),
+            None,
+        ).unwrap();
+
         let skybox_program = glium::Program::from_source(
             &display,
             &load_asset_str!("./shaders/skybox/vertex.glsl"),
@@ -149,11 +291,20 @@ impl Scene {
 
         let model_points = VertexBuffer::new(&display, &model.vertices).unwrap();
         let model_normals = VertexBuffer::new(&display, &model.normals).unwrap();
-        let model_indices = IndexBuffer::new(
-            &display,
-            glium::index::PrimitiveType::TrianglesList,
-            &model.indices
-        ).unwrap();
+        let model_tex_coords = VertexBuffer::new(&display, &model.tex_coords).unwrap();
+        let has_vertex_colors = !model.colors.is_empty();
+        let model_colors = color_buffer(&display, &model);
+        let is_point_cloud = model.indices.len() == 0 && model.points.len() > 0;
+        let model_parts = model_parts(&display, &model);
+        let group_names = model.groups.iter().map(|g| g.label()).collect::<Vec<_>>();
+        let model_edges = index_buffer(&display, PrimitiveType::LinesList, &model.edges);
+        let model_lines = optional_index_buffer(&display, PrimitiveType::LinesList, &model.lines);
+        let model_point_set = optional_index_buffer(&display, PrimitiveType::Points, &model.points);
+        let model_materials = model.materials.iter()
+                                             .map(|m| SceneMaterial::new(&display, m))
+                                             .collect();
+        let default_material = SceneMaterial::new(&display, &mtl::Material::new(""));
+        let white_texture = Texture2d::new(&display, vec![vec![(255u8, 255u8, 255u8, 255u8)]]).unwrap();
 
         let skybox_images = load_skybox_images();
         let skybox_texture = unsafe {
@@ -196,6 +347,27 @@ impl Scene {
             },
             polygon_mode: glium::draw_parameters::PolygonMode::Fill,
             backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            blend: glium::Blend::alpha_blending(),
+            ..Default::default()
+        };
+
+        let overlay_parameters = DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLessOrEqual,
+                write: true,
+                ..Default::default()
+            },
+            point_size: Some(5.0),
+            ..Default::default()
+        };
+
+        let points_parameters = DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            point_size: Some(4.0),
             ..Default::default()
         };
 
@@ -244,23 +416,108 @@ impl Scene {
         ];
         let skybox_points = VertexBuffer::new(&display, &skybox_vertices).unwrap();
         let skybox_indices = glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList);
+        // The skybox is drawn first and at the far plane, behind everything.
+        let skybox_parameters = DrawParameters::default();
 
 
 
-        Scene {
+        let mut scene = Scene {
             light: Pnt3::new(1.0, -1.0, 1.0f32),
             camera_position: Pnt3::new(-0.03, -0.1, 0.4),
+            model_center: na::zero(),
+            camera_distance: 1.0,
+            projection: PerspMat3::new(1.0, FIELD_OF_VIEW, 0.1, 100.0),
+            model: model,
+            original_model: None,
+            normals: normals,
             display: display,
             draw_parameters: params,
             model_points: model_points,
             model_normals: model_normals,
-            model_indices: model_indices,
+            model_tex_coords: model_tex_coords,
+            model_colors: model_colors,
+            has_vertex_colors: has_vertex_colors,
+            model_parts: model_parts,
+            model_edges: model_edges,
+            model_materials: model_materials,
+            default_material: default_material,
+            white_texture: white_texture,
+            wireframe: false,
+            hidden_groups: vec![false; group_names.len()],
+            group_names: group_names,
+            selected_group: None,
+            isolate_selected: false,
             model_program: model_program,
+            model_lines: model_lines,
+            model_point_set: model_point_set,
+            overlay_program: overlay_program,
+            overlay_parameters: overlay_parameters,
+            is_point_cloud: is_point_cloud,
+            points_program: points_program,
+            points_parameters: points_parameters,
             skybox_points: skybox_points,
             skybox_indices: skybox_indices,
             skybox_texture: skybox_texture,
             skybox_program: skybox_program,
-        }
+            skybox_parameters: skybox_parameters,
+        };
+        scene.frame();
+        scene
+    }
+
+    /// Places the camera, the light and the near and far planes so that
+    /// the visible parts of the model fill the view, whichever way they
+    /// are rotated around their center.
+    fn frame(&mut self) {
+        let indices = {
+            let model = &self.model;
+            let visible = self.model_parts
+                              .iter()
+                              .filter(|p| self.is_group_visible(p.group))
+                              .flat_map(|p| p.range.clone())
+                              .map(|i| model.indices.get(i));
+            let lines = (0..model.lines.len()).map(|i| model.lines.get(i));
+            let points = (0..model.points.len()).map(|i| model.points.get(i));
+            visible.chain(lines).chain(points).collect::<Vec<_>>()
+        };
+        // With everything hidden, the whole model is framed.
+        let indices = if indices.is_empty() {
+            (0..self.model.vertices.len() as u32).collect()
+        } else {
+            indices
+        };
+        let (center, radius) = {
+            let positions = || indices.iter().map(|&i| self.model.vertices[i as usize].position);
+            match bounds::Aabb::of(positions()) {
+                Some(aabb) => {
+                    let sphere = bounds::Sphere::around(&aabb, positions());
+                    (sphere.center, sphere.radius)
+                }
+                None => (na::zero(), 0.0),
+            }
+        };
+        let radius = if radius > 0.0 { radius } else { 1.0 };
+
+        let distance = radius / (FIELD_OF_VIEW / 2.0).sin();
+        let camera = center + na::normalize(&FRAMING_DIRECTION) * distance;
+        // As far from the model as it was from the bunny it was set up for.
+        let light = center + Vec3::new(1.0, -1.0, 1.0) * (10.0 * radius);
+        self.model_center = center;
+        self.camera_distance = distance;
+        self.camera_position = Pnt3::new(camera.x, camera.y, camera.z);
+        self.light = Pnt3::new(light.x, light.y, light.z);
+        self.projection = PerspMat3::new(1.0, FIELD_OF_VIEW, (distance - radius) / 2.0, (distance + radius) * 2.0);
+    }
+
+    /// The view, with the model rotated around its center.
+    fn view(&self, rotation: &UnitQuat<f32>) -> Mat4<f32> {
+        let eye = na::normalize(&FRAMING_DIRECTION) * self.camera_distance;
+        let mut look_at = Iso3::one();
+        look_at.look_at_z(&Pnt3::new(eye.x, eye.y, eye.z),
+                          &Pnt3::new(0.0, 0.0, 0.0),
+                          &Vec3::new(0.0, 1.0, 0.0));
+        let to_center = Iso3::new(-self.model_center, na::zero());
+        na::to_homogeneous(&look_at) * na::to_homogeneous(&rotation.to_rot()) * na::to_homogeneous(&to_center)
     }
 
     fn draw(&self, view: &na::Mat4<f32>, projection: &na::Mat4<f32>) {
@@ -279,39 +536,246 @@ impl Scene {
                     &self.skybox_indices,
                     &self.skybox_program,
                     &uniforms,
-                    &self.draw_parameters).unwrap();
+                    &self.skybox_parameters).unwrap();
+
+        if self.wireframe {
+            self.draw_model(&mut target, &self.model_edges, None, false, view, projection);
+        } else {
+            for part in self.model_parts.iter().filter(|p| self.is_group_visible(p.group)) {
+                let highlight = part.group.is_some() && part.group == self.selected_group;
+                self.draw_model(&mut target, &part.indices, part.material, highlight, view, projection);
+            }
+        }
+
+        let overlay_uniforms = uniform! {
+            proj: *projection,
+            view: *view,
+            overlay_color: (1.0, 1.0, 0.0, 1.0f32),
+        };
+        for indices in &self.model_lines {
+            target.draw(&self.model_points,
+                        indices,
+                        &self.overlay_program,
+                        &overlay_uniforms,
+                        &self.overlay_parameters).unwrap();
+        }
+        if let Some(ref indices) = self.model_point_set {
+            if self.is_point_cloud {
+                let points_uniforms = uniform! {
+                    proj: *projection,
+                    view: *view,
+                };
+                target.draw((&self.model_points, &self.model_colors),
+                            indices,
+                            &self.points_program,
+                            &points_uniforms,
+                            &self.points_parameters).unwrap();
+            } else {
+                target.draw(&self.model_points,
+                            indices,
+                            &self.overlay_program,
+                            &overlay_uniforms,
+                            &self.overlay_parameters).unwrap();
+            }
+        }
+
+        target.finish().unwrap();
+    }
 
-        target.draw((&self.model_points, &self.model_normals),
-                    &self.model_indices,
+    /// Shows another version of the model, keeping the camera where it is.
+    /// The groups stay hidden and selected if there are as many as before.
+    /// Returns the previous model.
+    fn set_model(&mut self, model: obj::Obj) -> obj::Obj {
+        self.model_points = VertexBuffer::new(&self.display, &model.vertices).unwrap();
+        self.model_normals = VertexBuffer::new(&self.display, &model.normals).unwrap();
+        self.model_tex_coords = VertexBuffer::new(&self.display, &model.tex_coords).unwrap();
+        self.has_vertex_colors = !model.colors.is_empty();
+        self.model_colors = color_buffer(&self.display, &model);
+        self.is_point_cloud = model.indices.len() == 0 && model.points.len() > 0;
+        self.model_parts = model_parts(&self.display, &model);
+        self.model_edges = index_buffer(&self.display, PrimitiveType::LinesList, &model.edges);
+        self.model_lines = optional_index_buffer(&self.display, PrimitiveType::LinesList, &model.lines);
+        self.model_point_set = optional_index_buffer(&self.display, PrimitiveType::Points, &model.points);
+        self.model_materials = {
+            let display = &self.display;
+            model.materials.iter().map(|m| SceneMaterial::new(display, m)).collect()
+        };
+        let group_names = model.groups.iter().map(|g| g.label()).collect::<Vec<_>>();
+        if group_names.len() != self.group_names.len() {
+            self.hidden_groups = vec![false; group_names.len()];
+            self.selected_group = None;
+        }
+        self.group_names = group_names;
+        mem::replace(&mut self.model, model)
+    }
+
+    /// Halves the number of triangles of the model.
+    fn simplify(&mut self) {
+        let triangles = self.model.indices.len() / 3;
+        if triangles == 0 {
+            return;
+        }
+        let options = simplify::SimplifyOptions {
+            target_triangles: triangles / 2,
+            normals: self.normals,
+            ..Default::default()
+        };
+        match simplify::simplify(&self.model, &options) {
+            Ok(model) => {
+                println!("Simplified {} triangles to {}", triangles, model.indices.len() / 3);
+                let previous = self.set_model(model);
+                if self.original_model.is_none() {
+                    self.original_model = Some(previous);
+                }
+            }
+            Err(err) => println!("Could not simplify the model: {}", err),
+        }
+    }
+
+    fn restore_original_model(&mut self) {
+        if let Some(model) = self.original_model.take() {
+            println!("Restored {} triangles", model.indices.len() / 3);
+            self.set_model(model);
+        }
+    }
+
+    fn is_group_visible(&self, group: Option<usize>) -> bool {
+        if self.isolate_selected && self.selected_group.is_some() {
+            return group == self.selected_group;
+        }
+        group.map_or(true, |g| !self.hidden_groups[g])
+    }
+
+    /// Cycles the selection through the groups and "nothing selected".
+    fn select_next_group(&mut self) {
+        self.selected_group = match self.selected_group {
+            None if !self.group_names.is_empty() => Some(0),
+            Some(g) if g + 1 < self.group_names.len() => Some(g + 1),
+            _ => None,
+        };
+        match self.selected_group {
+            Some(g) => println!("Selected {}", self.group_names[g]),
+            None => println!("Selected nothing"),
+        }
+    }
+
+    fn toggle_selected_group(&mut self) {
+        if let Some(g) = self.selected_group {
+            self.hidden_groups[g] = !self.hidden_groups[g];
+        }
+    }
+
+    fn draw_model<'a, I>(&self,
+                         target: &mut glium::Frame,
+                         indices: I,
+                         material: Option<usize>,
+                         highlight: bool,
+                         view: &na::Mat4<f32>,
+                         projection: &na::Mat4<f32>)
+        where I: Into<IndicesSource<'a>>
+    {
+        let m = material.map_or(&self.default_material, |i| &self.model_materials[i]);
+        let uniforms = uniform! {
+            proj: *projection,
+            view: *view,
+            light: self.light,
+            camera_position: self.camera_position,
+            skybox: &self.skybox_texture,
+            use_material: material.is_some(),
+            use_vertex_colors: self.has_vertex_colors,
+            highlight: highlight,
+            ambient: m.ambient,
+            diffuse: m.diffuse,
+            specular: m.specular,
+            shininess: m.shininess,
+            dissolve: m.dissolve,
+            illum: m.illum,
+            diffuse_map: m.diffuse_map.as_ref().unwrap_or(&self.white_texture),
+            specular_map: m.specular_map.as_ref().unwrap_or(&self.white_texture),
+        };
+
+        target.draw((&self.model_points, &self.model_normals, &self.model_tex_coords, &self.model_colors),
+                    indices,
                     &self.model_program,
                     &uniforms,
                     &self.draw_parameters).unwrap();
-
-        target.finish().unwrap();
     }
 }
 
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: bunny model.obj");
-        process::exit(-1);
+    let args: Vec<_> = env::args().skip(1).collect();
+    let normals = match args.iter().filter(|a| a.starts_with("--normals=")).last() {
+        Some(arg) => parse_normal_mode(&arg["--normals=".len()..]).unwrap_or_else(|| usage()),
+        None => Default::default(),
+    };
+    let threads = match args.iter().filter(|a| a.starts_with("--threads=")).last() {
+        Some(arg) => arg["--threads=".len()..].parse().unwrap_or_else(|_| usage()),
+        None => 1,
+    };
+    let options = obj::LoadOptions {
+        lenient: args.iter().any(|a| a == "--lenient"),
+        normals: normals,
+        threads: threads,
+    };
+    let transform = parse_transform(&args);
+    let target_triangles = match args.iter().filter(|a| a.starts_with("--simplify=")).last() {
+        Some(arg) => Some(arg["--simplify=".len()..].parse().unwrap_or_else(|_| usage())),
+        None => None,
+    };
+    let max_error = match args.iter().filter(|a| a.starts_with("--simplify-error=")).last() {
+        Some(arg) => Some(arg["--simplify-error=".len()..].parse().unwrap_or_else(|_| usage())),
+        None => None,
+    };
+    let paths: Vec<_> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    if paths.len() != 1 {
+        usage();
+    }
+    let path = paths[0];
+
+    let registry = loader::Registry::with_builtin_formats();
+    let model = if path == "-" {
+        let stdin = io::stdin();
+        registry.parse_reader(stdin.lock(), Path::new(""), &options)
+    } else if args.iter().any(|a| a == "--no-cache") {
+        registry.load_from_file(Path::new(path), &options)
+    } else {
+        cache::load_cached(Path::new(path), &options, |path, options| registry.load_from_file(path, options))
+    };
+    let mut model = match model {
+        Ok(model) => model,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(-1);
+        }
+    };
+    for warning in &model.warnings {
+        println!("Warning: {}", warning);
+    }
+    transform::apply(&mut model, &transform);
+    if target_triangles.is_some() || max_error.is_some() {
+        let options = simplify::SimplifyOptions {
+            target_triangles: target_triangles.unwrap_or(0),
+            max_error: max_error.unwrap_or(std::f32::INFINITY),
+            normals: normals,
+        };
+        model = match simplify::simplify(&model, &options) {
+            Ok(model) => model,
+            Err(err) => {
+                println!("Could not simplify the model: {}", err);
+                process::exit(-1);
+            }
+        };
+    }
+    if let Some(arg) = args.iter().filter(|a| a.starts_with("--save=")).last() {
+        let ascii = args.iter().any(|a| a == "--ascii");
+        if let Err(err) = save_model(&model, Path::new(&arg["--save=".len()..]), ascii) {
+            println!("Could not save the model: {}", err);
+            process::exit(-1);
+        }
     }
-    let ref path = args[1];
-
-    let model = obj::load_from_file(path).unwrap();
-
-    let scene = Scene::new(model);
 
-    let proj = PerspMat3::<f32>::new(1.0, f32::pi() / 4.0, 0.1, 100.0);
-    let view: Mat4<f32> = na::to_homogeneous(&{
-        let mut transform = Iso3::one();
-        transform.look_at_z(&scene.camera_position,
-                            &Pnt3::new(0.0, 0.0, 0.0),
-                            &Vec3::new(0.0, 1.0, 0.0));
-        transform
-    });
+    let mut scene = Scene::new(model, normals);
 
     let mut mouse_tracker = MouseTracker::new();
     let mut rot = UnitQuat::new(Vec3::new(0.0, 0.0, 0.0));
@@ -320,17 +784,140 @@ fn main() {
             mouse_tracker.record_event(&ev);
             match ev {
                 Event::Closed => return,
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::W)) =>
+                    scene.wireframe = !scene.wireframe,
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Tab)) =>
+                    scene.select_next_group(),
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::H)) =>
+                    scene.toggle_selected_group(),
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::I)) =>
+                    scene.isolate_selected = !scene.isolate_selected,
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::D)) =>
+                    scene.simplify(),
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::R)) =>
+                    scene.restore_original_model(),
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::F)) => {
+                    scene.frame();
+                    rot = UnitQuat::new(Vec3::new(0.0, 0.0, 0.0));
+                }
                 _ => (),
             }
         }
         let (dx, dy) = mouse_tracker.drag_amount();
         rot = rot.append_rotation(&Vec3::new(dy, dx, 0.0));
-        let rot = na::to_homogeneous(&rot.to_rot());
-        let view = view * rot;
+        let view = scene.view(&rot);
+
+        scene.draw(&view, scene.projection.as_mat());
+    }
+
+}
+
+/// Writes STL to `.stl` files, binary unless `ascii` is set, and OBJ to
+/// anything else.
+fn save_model(model: &obj::Obj, path: &Path, ascii: bool) -> io::Result<()> {
+    match lowercase_extension(path).as_ref().map(|ext| &ext[..]) {
+        Some("stl") if ascii => stl::save_ascii_to_file(model, path, &Default::default()),
+        Some("stl") => stl::save_to_file(model, path),
+        _ => obj::save_to_file(model, path, &Default::default()),
+    }
+}
+
+fn lowercase_extension(path: &Path) -> Option<String> {
+    path.extension().map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+fn usage() -> ! {
+    println!("Usage: bunny [--lenient] [--normals=MODE] [--threads=N] [--no-cache] [--save=OUT.obj [--ascii]] [TRANSFORMS]");
+    println!("             [--simplify=TRIANGLES] [--simplify-error=DISTANCE] MODEL");
+    println!("MODEL is an `.obj`, `.ply`, `.stl`, `.gltf`, `.glb` or `.off` file, or an `.xyz` or `.pts` point cloud,");
+    println!("possibly gzip compressed, or `-` to read it from the standard input.");
+    println!("Files with other extensions are recognized by their contents, or else read as OBJ.");
+    println!("`--save` writes the loaded model, with generated normals, as OBJ or, to `.stl` files, as STL.");
+    println!("`--ascii` writes ASCII instead of binary STL.");
+    println!("`--threads` parses large models on several threads.");
+    println!("Parsed models are cached in `model.obj.cache`, unless `--no-cache` is given.");
+    println!("MODE is used for models without normals, it is one of");
+    println!("`flat`, `area`, `angle` (default) or `crease:DEGREES`.");
+    println!("TRANSFORMS are applied after loading and before saving, in this order:");
+    println!("`--up=z-to-y` or `--up=y-to-z` converts the up axis, `--recenter` moves the model to the origin,");
+    println!("`--normalize` scales it to unit size, `--scale=S` or `--scale=X,Y,Z`, `--rotate=X,Y,Z,DEGREES`");
+    println!("around an axis, and `--translate=X,Y,Z`.");
+    println!("`--simplify` then collapses edges until the model has at most TRIANGLES triangles,");
+    println!("`--simplify-error` stops before the surface moves further than DISTANCE.");
+    process::exit(-1);
+}
+
+fn parse_transform(args: &[String]) -> transform::Transform {
+    use bunny::transform::AxisConversion;
+
+    let value = |name: &str| args.iter().filter(|a| a.starts_with(name)).last().map(|a| &a[name.len()..]);
+    let numbers = |s: &str, counts: &[usize]| {
+        let numbers = s.split(',').map(|n| n.parse::<f32>()).collect::<Result<Vec<_>, _>>().unwrap_or_else(|_| usage());
+        if !counts.contains(&numbers.len()) {
+            usage();
+        }
+        numbers
+    };
+    transform::Transform {
+        convert_axes: value("--up=").map(|s| match s {
+            "z-to-y" => AxisConversion::ZUpToYUp,
+            "y-to-z" => AxisConversion::YUpToZUp,
+            _ => usage(),
+        }),
+        recenter: args.iter().any(|a| a == "--recenter"),
+        normalize: args.iter().any(|a| a == "--normalize"),
+        scale: value("--scale=").map(|s| {
+            let v = numbers(s, &[1, 3]);
+            if v.len() == 1 { Vec3::new(v[0], v[0], v[0]) } else { Vec3::new(v[0], v[1], v[2]) }
+        }),
+        rotate: value("--rotate=").map(|s| {
+            let v = numbers(s, &[4]);
+            (Vec3::new(v[0], v[1], v[2]), v[3].to_radians())
+        }),
+        translate: value("--translate=").map(|s| {
+            let v = numbers(s, &[3]);
+            Vec3::new(v[0], v[1], v[2])
+        }),
+    }
+}
+
+fn parse_normal_mode(s: &str) -> Option<normals::NormalMode> {
+    use bunny::normals::NormalMode;
 
-        scene.draw(&view, proj.as_mat());
+    match s {
+        "flat" => Some(NormalMode::Flat),
+        "area" => Some(NormalMode::AreaWeighted),
+        "angle" => Some(NormalMode::AngleWeighted),
+        _ if s.starts_with("crease:") => {
+            s["crease:".len()..].parse::<f32>().ok().map(|deg| NormalMode::Creased(deg.to_radians()))
+        }
+        _ => None,
     }
+}
+
+fn index_buffer(display: &Display, primitives: PrimitiveType, indices: &obj::Indices) -> IndexBufferAny {
+    match *indices {
+        obj::Indices::U16(ref indices) => IndexBuffer::new(display, primitives, indices).unwrap().into(),
+        obj::Indices::U32(ref indices) => IndexBuffer::new(display, primitives, indices).unwrap().into(),
+    }
+}
 
+fn optional_index_buffer(display: &Display, primitives: PrimitiveType, indices: &obj::Indices) -> Option<IndexBufferAny> {
+    if indices.len() > 0 {
+        Some(index_buffer(display, primitives, indices))
+    } else {
+        None
+    }
+}
+
+/// The colors of the vertices, white for models without colors.
+fn color_buffer(display: &Display, model: &obj::Obj) -> VertexBuffer<obj::Color> {
+    if !model.colors.is_empty() {
+        VertexBuffer::new(display, &model.colors).unwrap()
+    } else {
+        let white = obj::Color::from(na::Vec4::new(1.0, 1.0, 1.0, 1.0));
+        VertexBuffer::new(display, &vec![white; model.vertices.len()]).unwrap()
+    }
 }
 
 fn build_display() -> Display {
@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write, BufWriter};
+use std::fs::File;
+use std::fmt;
+use std::sync::Arc;
+
+use na::Vec3;
+
+use obj::{Line, Result, WriteOptions};
+
+/// A texture map, either an image file or an image embedded in the model.
+#[derive(Clone, PartialEq)]
+pub enum TextureMap {
+    File(PathBuf),
+    /// Encoded image data, such as a PNG or a JPEG.
+    Embedded(Arc<Vec<u8>>),
+}
+
+impl TextureMap {
+    /// The usual extension of an embedded image, judged by its signature.
+    fn extension(data: &[u8]) -> &'static str {
+        if data.starts_with(b"\xff\xd8\xff") { "jpg" } else { "png" }
+    }
+}
+
+impl fmt::Debug for TextureMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TextureMap::File(ref path) => write!(f, "File({:?})", path),
+            TextureMap::Embedded(ref data) => write!(f, "Embedded({} bytes)", data.len()),
+        }
+    }
+}
+
+/// A material from an `.mtl` library. Texture maps are resolved
+/// relative to the library file.
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name: String,
+    pub ambient: Vec3<f32>,
+    pub diffuse: Vec3<f32>,
+    pub specular: Vec3<f32>,
+    pub shininess: f32,
+    pub dissolve: f32,
+    pub illum: u32,
+    pub ambient_map: Option<TextureMap>,
+    pub diffuse_map: Option<TextureMap>,
+    pub specular_map: Option<TextureMap>,
+    pub shininess_map: Option<TextureMap>,
+    pub dissolve_map: Option<TextureMap>,
+    pub bump_map: Option<TextureMap>,
+}
+
+impl Material {
+    pub fn new(name: &str) -> Material {
+        Material {
+            name: name.to_owned(),
+            ambient: Vec3::new(0.2, 0.2, 0.2),
+            diffuse: Vec3::new(0.8, 0.8, 0.8),
+            specular: Vec3::new(0.0, 0.0, 0.0),
+            shininess: 1.0,
+            dissolve: 1.0,
+            illum: 1,
+            ambient_map: None,
+            diffuse_map: None,
+            specular_map: None,
+            shininess_map: None,
+            dissolve_map: None,
+            bump_map: None,
+        }
+    }
+}
+
+
+pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> Result<Vec<Material>> {
+    let file_path = file_path.as_ref();
+    let mut file = try!(File::open(file_path));
+    let mut contents = Vec::new();
+    try!(file.read_to_end(&mut contents));
+    parse(&String::from_utf8_lossy(&contents), file_path.parent().unwrap_or(Path::new("")))
+}
+
+/// Saves the library, along with the embedded texture maps, which are
+/// written into image files next to it.
+pub fn save_to_file<P: AsRef<Path>>(materials: &[Material], file_path: P, options: &WriteOptions) -> io::Result<()> {
+    let file_path = file_path.as_ref();
+    let dir = file_path.parent().unwrap_or(Path::new(""));
+    let stem = file_path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+
+    let mut materials = materials.to_vec();
+    for (i, m) in materials.iter_mut().enumerate() {
+        let mut maps = [("Ka", &mut m.ambient_map),
+                        ("Kd", &mut m.diffuse_map),
+                        ("Ks", &mut m.specular_map),
+                        ("Ns", &mut m.shininess_map),
+                        ("d", &mut m.dissolve_map),
+                        ("bump", &mut m.bump_map)];
+        for &mut (key, ref mut map) in &mut maps {
+            let path = match **map {
+                Some(TextureMap::Embedded(ref data)) => {
+                    let path = dir.join(format!("{}_{}_{}.{}", stem, i, key, TextureMap::extension(data)));
+                    try!(try!(File::create(&path)).write_all(data));
+                    path
+                }
+                _ => continue,
+            };
+            **map = Some(TextureMap::File(path));
+        }
+    }
+
+    let mut out = BufWriter::new(try!(File::create(file_path)));
+    write(&materials, &mut out, dir, options)
+}
+
+/// Writes the materials, texture paths are made relative to `dir` when possible.
+/// Embedded texture maps are left out.
+pub fn write<W: Write>(materials: &[Material], out: &mut W, dir: &Path, options: &WriteOptions) -> io::Result<()> {
+    let p = options.precision;
+    for m in materials {
+        try!(writeln!(out, "newmtl {}", m.name));
+        for &(key, color) in &[("Ka", m.ambient), ("Kd", m.diffuse), ("Ks", m.specular)] {
+            try!(writeln!(out, "{} {:.*} {:.*} {:.*}", key, p, color.x, p, color.y, p, color.z));
+        }
+        try!(writeln!(out, "Ns {:.*}", p, m.shininess));
+        try!(writeln!(out, "d {:.*}", p, m.dissolve));
+        try!(writeln!(out, "illum {}", m.illum));
+        let maps = [("map_Ka", &m.ambient_map),
+                    ("map_Kd", &m.diffuse_map),
+                    ("map_Ks", &m.specular_map),
+                    ("map_Ns", &m.shininess_map),
+                    ("map_d", &m.dissolve_map),
+                    ("map_bump", &m.bump_map)];
+        for &(key, map) in &maps {
+            if let Some(TextureMap::File(ref path)) = *map {
+                let path = path.strip_prefix(dir).unwrap_or(path);
+                try!(writeln!(out, "{} {}", key, path.display()));
+            }
+        }
+        try!(writeln!(out, ""));
+    }
+    Ok(())
+}
+
+fn parse(data: &str, dir: &Path) -> Result<Vec<Material>> {
+    let mut result: Vec<Material> = Vec::new();
+
+    for (i, text) in data.lines().enumerate() {
+        let line = Line { number: i + 1, text: text };
+        let words = text.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() || words[0].starts_with('#') {
+            continue;
+        }
+        if words[0] == "newmtl" {
+            result.push(Material::new(text.trim()["newmtl".len()..].trim()));
+            continue;
+        }
+
+        let material = try!(result.last_mut().ok_or_else(|| line.syntax_error(words[0], "`newmtl`")));
+        let args = &words[1..];
+        match words[0] {
+            "Ka" => material.ambient = try!(parse_color(line, args)),
+            "Kd" => material.diffuse = try!(parse_color(line, args)),
+            "Ks" => material.specular = try!(parse_color(line, args)),
+            "Ns" => material.shininess = try!(parse_float(line, args)),
+            "d" => material.dissolve = try!(parse_float(line, args)),
+            "Tr" => material.dissolve = 1.0 - try!(parse_float(line, args)),
+            "illum" => {
+                let token = args.get(0).cloned().unwrap_or(line.end());
+                material.illum = try!(token.parse().map_err(|_| line.syntax_error(token, "an illumination model")))
+            }
+            "map_Ka" => material.ambient_map = Some(try!(parse_map(line, args, dir))),
+            "map_Kd" => material.diffuse_map = Some(try!(parse_map(line, args, dir))),
+            "map_Ks" => material.specular_map = Some(try!(parse_map(line, args, dir))),
+            "map_Ns" => material.shininess_map = Some(try!(parse_map(line, args, dir))),
+            "map_d" => material.dissolve_map = Some(try!(parse_map(line, args, dir))),
+            "map_bump" | "map_Bump" | "bump" => material.bump_map = Some(try!(parse_map(line, args, dir))),
+            _ => (),
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_float(line: Line, args: &[&str]) -> Result<f32> {
+    if args.len() != 1 {
+        let token = args.get(1).cloned().unwrap_or(line.end());
+        return Err(line.syntax_error(token, "a single number"));
+    }
+    args[0].parse().map_err(|_| line.syntax_error(args[0], "a number"))
+}
+
+/// Parses `r g b`, or a single `r` meaning gray.
+fn parse_color(line: Line, args: &[&str]) -> Result<Vec3<f32>> {
+    let rgb = try!(args.iter()
+                       .map(|s| s.parse::<f32>().map_err(|_| line.syntax_error(s, "a number")))
+                       .collect::<Result<Vec<_>>>());
+    match rgb.len() {
+        1 => Ok(Vec3::new(rgb[0], rgb[0], rgb[0])),
+        3 => Ok(Vec3::new(rgb[0], rgb[1], rgb[2])),
+        _ => {
+            let token = args.get(3).cloned().unwrap_or(line.end());
+            Err(line.syntax_error(token, "one or three color components"))
+        }
+    }
+}
+
+/// Texture options such as `-s 2 2 1` are skipped, the file name comes last.
+fn parse_map(line: Line, args: &[&str], dir: &Path) -> Result<TextureMap> {
+    let name = try!(args.last().ok_or_else(|| line.syntax_error(line.end(), "a file name")));
+    Ok(TextureMap::File(dir.join(name)))
+}
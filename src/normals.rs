@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::f32;
+
+use na::{self, Vec3};
+
+/// How to compute normals for the vertices which have none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalMode {
+    /// Every face gets the normal of its plane.
+    Flat,
+    /// Normals of adjacent faces are averaged, weighted by face area.
+    AreaWeighted,
+    /// Normals of adjacent faces are averaged, weighted by the angle
+    /// of the face at the vertex.
+    AngleWeighted,
+    /// Like `AngleWeighted`, but faces meeting at an angle larger than
+    /// the given one, in radians, are not smoothed together.
+    Creased(f32),
+}
+
+impl Default for NormalMode {
+    fn default() -> NormalMode {
+        NormalMode::AngleWeighted
+    }
+}
+
+/// Fills in `normals` of the vertices flagged as `missing`.
+///
+/// `positions` and `position_ids` are per vertex, vertices with equal ids
+/// share a position and are smoothed together even if other attributes
+/// differ. A vertex which ends up with several different normals is split:
+/// copies are appended to `normals`, `triangles` are updated to use them,
+/// and for each copy the returned vector holds the vertex it was made from,
+/// so that the caller can duplicate the rest of the attributes.
+///
+/// `smoothing_groups` is either empty or has a group for every triangle.
+/// In the latter case only faces from the same group are smoothed
+/// together, and faces from group zero are flat.
+pub fn generate(mode: NormalMode,
+                positions: &[Vec3<f32>],
+                position_ids: &[usize],
+                missing: &[bool],
+                smoothing_groups: &[u32],
+                triangles: &mut [u32],
+                normals: &mut Vec<Vec3<f32>>)
+                -> Vec<u32> {
+    let faces = triangles.chunks(3).map(|t| Face::new(positions, t)).collect::<Vec<_>>();
+    let position_count = position_ids.iter().map(|&id| id + 1).max().unwrap_or(0);
+
+    // Faces around each position, with the corner of the face at the position.
+    let mut around = vec![Vec::new(); position_count];
+    for (f, t) in triangles.chunks(3).enumerate() {
+        for (corner, &v) in t.iter().enumerate() {
+            around[position_ids[v as usize]].push((f, corner));
+        }
+    }
+
+    // Without smoothing groups and creases, all faces around a position
+    // are smoothed together, so the normals can be computed upfront.
+    let smooth = match mode {
+        NormalMode::AreaWeighted | NormalMode::AngleWeighted if smoothing_groups.is_empty() => {
+            around.iter()
+                  .map(|fs| normalize_or_zero(sum(fs.iter().map(|&(f, c)| faces[f].weight(mode, c)))))
+                  .collect::<Vec<_>>()
+        }
+        _ => Vec::new(),
+    };
+    let min_cos = match mode {
+        NormalMode::Creased(angle) => angle.cos(),
+        _ => f32::NEG_INFINITY,
+    };
+    let smoothed_together = |f: usize, g: usize| {
+        if !smoothing_groups.is_empty() &&
+           (smoothing_groups[f] == 0 || smoothing_groups[f] != smoothing_groups[g]) {
+            return f == g;
+        }
+        na::dot(&faces[f].unit, &faces[g].unit) >= min_cos
+    };
+
+    let mut splits = Vec::new();
+    let mut assigned = vec![false; missing.len()];
+    let mut cache = HashMap::new();
+    for i in 0..triangles.len() {
+        let v = triangles[i] as usize;
+        if !missing[v] {
+            continue;
+        }
+        let f = i / 3;
+        let normal = if mode == NormalMode::Flat {
+            faces[f].unit
+        } else if !smooth.is_empty() {
+            smooth[position_ids[v]]
+        } else {
+            let adjacent = around[position_ids[v]].iter().filter(|&&(g, _)| smoothed_together(f, g));
+            normalize_or_zero(sum(adjacent.map(|&(g, c)| faces[g].weight(mode, c))))
+        };
+        let normal = if normal == na::zero() { faces[f].unit } else { normal };
+
+        // Nearly equal normals, like those of the two halves of a flat quad, are merged.
+        let quantize = |x: f32| (x * 1e5).round() as i32;
+        let key = (v, [quantize(normal.x), quantize(normal.y), quantize(normal.z)]);
+        let index = match cache.get(&key) {
+            Some(&index) => index,
+            None if !assigned[v] => {
+                assigned[v] = true;
+                normals[v] = normal;
+                v as u32
+            }
+            None => {
+                normals.push(normal);
+                splits.push(v as u32);
+                (normals.len() - 1) as u32
+            }
+        };
+        cache.insert(key, index);
+        triangles[i] = index;
+    }
+    splits
+}
+
+struct Face {
+    /// Cross product of the edges, its length is twice the area.
+    cross: Vec3<f32>,
+    unit: Vec3<f32>,
+    angles: [f32; 3],
+}
+
+impl Face {
+    fn new(positions: &[Vec3<f32>], t: &[u32]) -> Face {
+        let p = [positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]];
+        let cross = na::cross(&(p[1] - p[0]), &(p[2] - p[0]));
+        let angle = |i: usize| {
+            let a = normalize_or_zero(p[(i + 1) % 3] - p[i]);
+            let b = normalize_or_zero(p[(i + 2) % 3] - p[i]);
+            na::dot(&a, &b).max(-1.0).min(1.0).acos()
+        };
+        Face {
+            cross: cross,
+            unit: normalize_or_zero(cross),
+            angles: [angle(0), angle(1), angle(2)],
+        }
+    }
+
+    fn weight(&self, mode: NormalMode, corner: usize) -> Vec3<f32> {
+        match mode {
+            NormalMode::AreaWeighted => self.cross,
+            _ => self.unit * self.angles[corner],
+        }
+    }
+}
+
+fn sum<I: Iterator<Item = Vec3<f32>>>(vectors: I) -> Vec3<f32> {
+    vectors.fold(na::zero(), |acc, v| acc + v)
+}
+
+pub fn normalize_or_zero(v: Vec3<f32>) -> Vec3<f32> {
+    let norm = na::norm(&v);
+    if norm > f32::EPSILON { v / norm } else { na::zero() }
+}
@@ -1,15 +1,28 @@
-use std::path::Path;
-use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufWriter, Write};
 use std::fs::File;
 use std::fmt;
 use std::error;
 use std::result;
+use std::cmp;
+use std::u16;
+use std::u32;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::borrow::Cow;
 
-use na::Vec3;
+use crossbeam;
+use na::{Vec3, Vec4};
+
+use float;
+use fnv::FnvBuildHasher;
+use mtl::{self, Material};
+use normals::{self, NormalMode};
+use triangulate::triangulate;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Vertex {
-    position: Vec3<f32>,
+    pub position: Vec3<f32>,
 }
 implement_vertex!(Vertex, position);
 
@@ -22,7 +35,7 @@ impl From<Vec3<f32>> for Vertex {
 
 #[derive(Debug, Clone, Copy)]
 pub struct Normal {
-    normal: Vec3<f32>,
+    pub normal: Vec3<f32>,
 }
 implement_vertex!(Normal, normal);
 
@@ -32,100 +45,1302 @@ impl From<Vec3<f32>> for Normal {
     }
 }
 
+/// Texture coordinates `(u, v, w)`, missing components default to zero.
+#[derive(Debug, Clone, Copy)]
+pub struct TexCoord {
+    pub tex_coord: Vec3<f32>,
+}
+implement_vertex!(TexCoord, tex_coord);
+
+impl From<Vec3<f32>> for TexCoord {
+    fn from(t: Vec3<f32>) -> TexCoord {
+        TexCoord { tex_coord: t }
+    }
+}
+
+/// Vertex color `(r, g, b, a)`, components range from zero to one.
+#[derive(Debug, Clone, Copy)]
+pub struct Color {
+    pub color: Vec4<f32>,
+}
+implement_vertex!(Color, color);
+
+impl From<Vec4<f32>> for Color {
+    fn from(c: Vec4<f32>) -> Color {
+        Color { color: c }
+    }
+}
+
+/// Vertex indices, stored as `u16` whenever the mesh is small enough.
+#[derive(Debug, Clone)]
+pub enum Indices {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl Indices {
+    pub fn new(indices: Vec<u32>, vertex_count: usize) -> Indices {
+        if vertex_count <= u16::MAX as usize {
+            Indices::U16(indices.into_iter().map(|i| i as u16).collect())
+        } else {
+            Indices::U32(indices)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Indices::U16(ref indices) => indices.len(),
+            Indices::U32(ref indices) => indices.len(),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> u32 {
+        match *self {
+            Indices::U16(ref indices) => indices[i] as u32,
+            Indices::U32(ref indices) => indices[i],
+        }
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> Indices {
+        match *self {
+            Indices::U16(ref indices) => Indices::U16(indices[range].to_vec()),
+            Indices::U32(ref indices) => Indices::U32(indices[range].to_vec()),
+        }
+    }
+}
+
+/// A run of `Obj::indices` drawn with the same material.
+#[derive(Debug, Clone)]
+pub struct MaterialRange {
+    /// Index into `Obj::materials`, `None` if no known material was set.
+    pub material: Option<usize>,
+    pub range: Range<usize>,
+}
+
+/// A named part of the model, started by an `o` or a `g` statement.
+#[derive(Debug, Clone)]
+pub struct Group {
+    /// Name of the enclosing `o` object, empty if there is none.
+    pub object: String,
+    /// Name from the `g` statement, empty for the object itself.
+    pub name: String,
+    pub range: Range<usize>,
+}
+
+impl Group {
+    pub fn label(&self) -> String {
+        match (self.object.is_empty(), self.name.is_empty()) {
+            (false, false) => format!("{}/{}", self.object, self.name),
+            (true, _) => self.name.clone(),
+            (false, true) => self.object.clone(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Obj {
     pub vertices: Vec<Vertex>,
     pub normals: Vec<Normal>,
-    pub indices: Vec<u16>,
+    pub tex_coords: Vec<TexCoord>,
+    /// Empty unless the file has colored vertices.
+    pub colors: Vec<Color>,
+    pub indices: Indices,
+    /// Pairs of indices tracing the edges of the faces as they were
+    /// written in the file, before triangulation.
+    pub edges: Indices,
+    /// Pairs of indices of the segments of `l` polylines.
+    pub lines: Indices,
+    /// Indices of `p` points.
+    pub points: Indices,
+    pub materials: Vec<Material>,
+    pub material_ranges: Vec<MaterialRange>,
+    /// Ranges of `indices` belonging to named objects and groups.
+    pub groups: Vec<Group>,
+    /// Errors in the lines skipped by a lenient load.
+    pub warnings: Vec<ObjError>,
+}
+
+impl Obj {
+    /// Checks that every index refers to an existing vertex, so that the
+    /// buffers can be handed to the GPU as they are.
+    pub fn validate(&self) -> Result<()> {
+        let count = self.vertices.len();
+        let buffers = [("face", &self.indices, 3),
+                       ("edge", &self.edges, 2),
+                       ("line", &self.lines, 2),
+                       ("point", &self.points, 1)];
+        for &(primitive, indices, size) in &buffers {
+            try!(check_indices(primitive, size, (0..indices.len()).map(|i| indices.get(i)), count));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the indices of the primitives, of `size` indices each, refer
+/// to the `count` vertices.
+fn check_indices<I: Iterator<Item = u32>>(primitive: &'static str, size: usize, indices: I, count: usize) -> Result<()> {
+    for (i, index) in indices.enumerate() {
+        if index as usize >= count {
+            return Err(ObjError::InvalidIndex {
+                primitive: primitive,
+                number: i / size,
+                index: index,
+                count: count,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the buffers of a model before `Indices::new` narrows them, which
+/// would turn indices past the end into valid ones.
+fn check_buffers(indices: &[u32], edges: &[u32], lines: &[u32], points: &[u32], count: usize) -> Result<()> {
+    try!(check_indices("face", 3, indices.iter().cloned(), count));
+    try!(check_indices("edge", 2, edges.iter().cloned(), count));
+    try!(check_indices("line", 2, lines.iter().cloned(), count));
+    check_indices("point", 1, points.iter().cloned(), count)
+}
+
+/// A mesh with a single index per vertex, as most formats other than OBJ
+/// store them, to be turned into an `Obj`.
+#[derive(Debug, Clone, Default)]
+pub struct IndexedMesh {
+    pub positions: Vec<Vec3<f32>>,
+    /// Per vertex attributes, either empty or as long as `positions`.
+    /// Missing and zero normals are generated, the rest default to zero.
+    pub normals: Vec<Vec3<f32>>,
+    pub tex_coords: Vec<Vec3<f32>>,
+    pub colors: Vec<Vec4<f32>>,
+    pub indices: Vec<u32>,
+    pub edges: Vec<u32>,
+    pub lines: Vec<u32>,
+    pub points: Vec<u32>,
+    pub materials: Vec<Material>,
+    /// Ranges of `indices`, all of them are drawn with no material if empty.
+    pub material_ranges: Vec<MaterialRange>,
+    pub groups: Vec<Group>,
+}
+
+impl IndexedMesh {
+    /// Adds a polygon, which must only refer to existing vertices.
+    pub fn add_polygon(&mut self, polygon: &[u32]) {
+        if polygon.len() == 3 {
+            self.indices.extend_from_slice(polygon);
+        } else {
+            let points = polygon.iter().map(|&i| self.positions[i as usize]).collect::<Vec<_>>();
+            for triangle in triangulate(&points) {
+                self.indices.extend(triangle.iter().map(|&i| polygon[i]));
+            }
+        }
+        for i in 0..polygon.len() {
+            self.edges.push(polygon[i]);
+            self.edges.push(polygon[(i + 1) % polygon.len()]);
+        }
+    }
+
+    pub fn into_obj(mut self, options: &LoadOptions) -> Result<Obj> {
+        let count = self.positions.len();
+        // Generating normals relies on the indices being valid. Vertices
+        // split meanwhile are only added.
+        try!(check_buffers(&self.indices, &self.edges, &self.lines, &self.points, count));
+        if self.tex_coords.is_empty() {
+            self.tex_coords = vec![Vec3::new(0.0, 0.0, 0.0); count];
+        }
+        if self.normals.is_empty() {
+            self.normals = vec![Vec3::new(0.0, 0.0, 0.0); count];
+        }
+        let missing = self.normals.iter().map(|&n| n == Vec3::new(0.0, 0.0, 0.0)).collect::<Vec<_>>();
+        if missing.iter().any(|&m| m) {
+            let position_ids = (0..count).collect::<Vec<_>>();
+            let splits = normals::generate(options.normals,
+                                           &self.positions,
+                                           &position_ids,
+                                           &missing,
+                                           &[],
+                                           &mut self.indices,
+                                           &mut self.normals);
+            for v in splits {
+                let v = v as usize;
+                let (position, tex_coord) = (self.positions[v], self.tex_coords[v]);
+                self.positions.push(position);
+                self.tex_coords.push(tex_coord);
+                if !self.colors.is_empty() {
+                    let color = self.colors[v];
+                    self.colors.push(color);
+                }
+            }
+        }
+
+        let vertex_count = self.positions.len();
+        let index_count = self.indices.len();
+        Ok(Obj {
+            vertices: self.positions.into_iter().map(Vertex::from).collect(),
+            normals: self.normals.into_iter().map(Normal::from).collect(),
+            tex_coords: self.tex_coords.into_iter().map(TexCoord::from).collect(),
+            colors: self.colors.into_iter().map(Color::from).collect(),
+            indices: Indices::new(self.indices, vertex_count),
+            edges: Indices::new(self.edges, vertex_count),
+            lines: Indices::new(self.lines, vertex_count),
+            points: Indices::new(self.points, vertex_count),
+            materials: self.materials,
+            material_ranges: if index_count == 0 || !self.material_ranges.is_empty() {
+                self.material_ranges
+            } else {
+                vec![MaterialRange { material: None, range: 0..index_count }]
+            },
+            groups: self.groups,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+/// Points to the offending token in the source.
+#[derive(Debug, Clone)]
+pub struct Location {
+    /// One-based line number.
+    pub line: usize,
+    /// One-based column of the token, in characters.
+    pub column: usize,
+    pub token: String,
+    /// The whole offending line.
+    pub source: String,
 }
 
 #[derive(Debug)]
 pub enum ObjError {
     Io(io::Error),
-    SyntaxError,
-    NotSupported,
+    SyntaxError { location: Location, expected: &'static str },
+    NotSupported { location: Location, feature: &'static str },
+    /// A face refers to an element which is not defined.
+    IndexOutOfRange { location: Location, index: i64, count: usize },
+    /// A loaded primitive refers to a vertex past the end of `Obj::vertices`.
+    /// `number` is the zero-based index of the `primitive` in its buffer.
+    InvalidIndex { primitive: &'static str, number: usize, index: u32, count: usize },
+    /// The file is well-formed, but its content is inconsistent, like a
+    /// glTF accessor reading past the end of its buffer.
+    Malformed(String),
+    /// A material library could not be read, which is only a warning.
+    MaterialLibrary { location: Location, path: PathBuf, cause: Box<ObjError> },
 }
 
 pub type Result<T> = result::Result<T, ObjError>;
 
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Number of digits after the decimal point.
+    pub precision: usize,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions { precision: 6 }
+    }
+}
 
-pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> Result<Obj> {
-    let mut file = try!(File::open(file_path));
-    let mut contents = String::new();
-    try!(file.read_to_string(&mut contents));
-    parse(&contents)
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Skip malformed lines, collecting the errors into `Obj::warnings`,
+    /// instead of failing the whole load.
+    pub lenient: bool,
+    /// How to compute normals which are missing from the file.
+    pub normals: NormalMode,
+    /// Number of threads parsing files which are in memory or memory-mapped.
+    /// Zero and one both mean the calling thread only.
+    pub threads: usize,
+}
+
+
+/// Parses OBJ source line by line, `dir` is used to find material libraries.
+pub fn parse_reader<R: BufRead>(mut reader: R, dir: &Path, options: &LoadOptions) -> Result<Obj> {
+    let mut parser = Parser::new(options);
+    let mut buffer = Vec::new();
+    let mut number = 0;
+    loop {
+        buffer.clear();
+        let first = number + 1;
+        loop {
+            let start = buffer.len();
+            if try!(reader.read_until(b'\n', &mut buffer)) == 0 {
+                break;
+            }
+            number += 1;
+            match continued(&buffer[start..]) {
+                Some(end) => {
+                    buffer.truncate(start + end);
+                    buffer.push(b' ');
+                }
+                None => break,
+            }
+        }
+        if buffer.is_empty() {
+            break;
+        }
+        try!(parser.raw_line(first, &buffer, dir));
+    }
+    parser.finish()
 }
 
-fn parse(data: &str) -> Result<Obj> {
-    let mut result = Obj {
-        vertices: Vec::new(),
-        normals: Vec::new(),
-        indices: Vec::new(),
+/// Parses OBJ source from memory, `dir` is used to find material libraries.
+pub fn parse_bytes(data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj> {
+    if options.threads > 1 {
+        return parse_parallel(data, dir, options);
+    }
+    let mut parser = Parser::new(options);
+    for (number, bytes) in JoinedLines::new(data) {
+        try!(parser.raw_line(number, &bytes, dir));
+    }
+    parser.finish()
+}
+
+/// Saves the model, and its materials into a `.mtl` file next to it.
+pub fn save_to_file<P: AsRef<Path>>(obj: &Obj, file_path: P, options: &WriteOptions) -> io::Result<()> {
+    let file_path = file_path.as_ref();
+    let mtllib = if obj.materials.is_empty() {
+        None
+    } else {
+        let mtl_path = file_path.with_extension("mtl");
+        try!(mtl::save_to_file(&obj.materials, &mtl_path, options));
+        mtl_path.file_name().map(|name| name.to_string_lossy().into_owned())
     };
+    let mut out = BufWriter::new(try!(File::create(file_path)));
+    write(obj, &mut out, mtllib.as_ref().map(|name| &name[..]), options)
+}
+
+/// Writes the model as OBJ, referring to the `mtllib` material library.
+///
+/// Every vertex is written with its own position, texture coordinate and
+/// normal, so parsing the output gives back the same vertices and indices.
+/// OBJ can't end an object or go back to no material, so groups without an
+/// object are only read back as such before the first object, and faces
+/// without a material use a name which is not in the library.
+pub fn write<W: Write>(obj: &Obj, out: &mut W, mtllib: Option<&str>, options: &WriteOptions) -> io::Result<()> {
+    let p = options.precision;
+    if let Some(name) = mtllib {
+        try!(writeln!(out, "mtllib {}", name));
+    }
+
+    for (i, v) in obj.vertices.iter().enumerate() {
+        let v = v.position;
+        match obj.colors.get(i) {
+            Some(c) => {
+                let c = c.color;
+                try!(writeln!(out, "v {:.*} {:.*} {:.*} {:.*} {:.*} {:.*} {:.*}",
+                              p, v.x, p, v.y, p, v.z, p, c.x, p, c.y, p, c.z, p, c.w))
+            }
+            None => try!(writeln!(out, "v {:.*} {:.*} {:.*}", p, v.x, p, v.y, p, v.z)),
+        }
+    }
+    let has_tex_coords = obj.tex_coords.iter().any(|t| t.tex_coord != Vec3::new(0.0, 0.0, 0.0));
+    if has_tex_coords {
+        for t in &obj.tex_coords {
+            let t = t.tex_coord;
+            if t.z == 0.0 {
+                try!(writeln!(out, "vt {:.*} {:.*}", p, t.x, p, t.y));
+            } else {
+                try!(writeln!(out, "vt {:.*} {:.*} {:.*}", p, t.x, p, t.y, p, t.z));
+            }
+        }
+    }
+    for n in &obj.normals {
+        let n = n.normal;
+        try!(writeln!(out, "vn {:.*} {:.*} {:.*}", p, n.x, p, n.y, p, n.z));
+    }
+
+    let mut groups = obj.groups.iter().peekable();
+    let mut materials = obj.material_ranges.iter().peekable();
+    let mut object = "";
+    let mut material = None;
+    let no_material = unused_material_name(&obj.materials);
+    for start in (0..obj.indices.len()).filter(|i| i % 3 == 0) {
+        while let Some(group) = groups.peek().cloned() {
+            if group.range.start > start {
+                break;
+            }
+            groups.next();
+            if !group.object.is_empty() && (group.object != object || group.name.is_empty()) {
+                object = &group.object;
+                try!(writeln!(out, "o {}", object));
+            }
+            if !group.name.is_empty() {
+                try!(writeln!(out, "g {}", group.name));
+            }
+        }
+        while let Some(range) = materials.peek().cloned() {
+            if range.range.start > start {
+                break;
+            }
+            materials.next();
+            if range.material != material {
+                let name = match range.material {
+                    Some(m) => &obj.materials[m].name,
+                    None => &no_material,
+                };
+                try!(writeln!(out, "usemtl {}", name));
+                material = range.material;
+            }
+        }
+
+        try!(write_element(out, "f", &obj.indices, start..start + 3, has_tex_coords));
+    }
+
+    for i in (0..obj.lines.len()).filter(|i| i % 2 == 0) {
+        try!(write_element(out, "l", &obj.lines, i..i + 2, has_tex_coords));
+    }
+    for i in 0..obj.points.len() {
+        try!(write_element(out, "p", &obj.points, i..i + 1, has_tex_coords));
+    }
+    Ok(())
+}
+
+/// A material name which none of the `materials` has.
+fn unused_material_name(materials: &[Material]) -> String {
+    let mut name = "default".to_owned();
+    while materials.iter().any(|m| m.name == name) {
+        name.push('_');
+    }
+    name
+}
+
+/// Writes the vertices with all their attributes, so that those of lines
+/// and points come back too.
+fn write_element<W: Write>(out: &mut W,
+                           keyword: &str,
+                           indices: &Indices,
+                           range: Range<usize>,
+                           has_tex_coords: bool)
+                           -> io::Result<()> {
+    try!(write!(out, "{}", keyword));
+    for i in range {
+        let i = indices.get(i) + 1;
+        if has_tex_coords {
+            try!(write!(out, " {}/{}/{}", i, i, i));
+        } else {
+            try!(write!(out, " {}//{}", i, i));
+        }
+    }
+    writeln!(out, "")
+}
+
+/// A line of the source, errors point into it.
+#[derive(Debug, Clone, Copy)]
+pub struct Line<'a> {
+    /// One-based line number.
+    pub number: usize,
+    pub text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    /// The `token` must be a slice of `self.text`, otherwise
+    /// the location points to the end of the line.
+    pub fn location(&self, token: &str) -> Location {
+        let offset = (token.as_ptr() as usize).wrapping_sub(self.text.as_ptr() as usize);
+        let offset = if offset <= self.text.len() { offset } else { self.text.len() };
+        Location {
+            line: self.number,
+            column: self.text[..offset].chars().count() + 1,
+            token: token.to_owned(),
+            source: self.text.to_owned(),
+        }
+    }
+
+    /// An empty token after the last one, for reporting missing input.
+    pub fn end(&self) -> &'a str {
+        &self.text[self.text.len()..]
+    }
+
+    pub fn syntax_error(&self, token: &str, expected: &'static str) -> ObjError {
+        ObjError::SyntaxError {
+            location: self.location(token),
+            expected: expected,
+        }
+    }
+}
+
+/// Indices of a position, texture coordinate and normal, as written in a face.
+/// Negative indices are relative to the end of the corresponding list.
+type RawVertex = (i64, Option<i64>, Option<i64>);
+
+/// Zero-based indices of a position, texture coordinate and normal.
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+/// Vertex attributes, as they are listed in the file.
+struct Attributes {
+    positions: Vec<Vec3<f32>>,
+    position_colors: Vec<Vec4<f32>>,
+    has_colors: bool,
+    normals: Vec<Vec3<f32>>,
+    tex_coords: Vec<Vec3<f32>>,
+}
+
+impl Attributes {
+    fn new() -> Attributes {
+        Attributes {
+            positions: Vec::new(),
+            position_colors: Vec::new(),
+            has_colors: false,
+            normals: Vec::new(),
+            tex_coords: Vec::new(),
+        }
+    }
+
+    /// Parses a `v`, `vn` or `vt` line, returns `false` for other keywords.
+    fn line<'a, I: Iterator<Item = &'a str>>(&mut self, keyword: &str, line: Line<'a>, args: I) -> Result<bool> {
+        match keyword {
+            "v" => {
+                let (position, color) = try!(parse_position(line, args));
+                self.positions.push(position);
+                self.position_colors.push(color.unwrap_or(Vec4::new(1.0, 1.0, 1.0, 1.0)));
+                self.has_colors |= color.is_some();
+            }
+            "vn" => self.normals.push(try!(parse_vec(line, args))),
+            "vt" => self.tex_coords.push(try!(parse_tex_coord(line, args))),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Numbers of positions, texture coordinates and normals.
+    fn counts(&self) -> [usize; 3] {
+        [self.positions.len(), self.tex_coords.len(), self.normals.len()]
+    }
+
+    fn append(&mut self, other: &mut Attributes) {
+        self.positions.append(&mut other.positions);
+        self.position_colors.append(&mut other.position_colors);
+        self.has_colors |= other.has_colors;
+        self.normals.append(&mut other.normals);
+        self.tex_coords.append(&mut other.tex_coords);
+    }
+}
+
+struct Parser {
+    attributes: Attributes,
+    /// Counts of attributes defined before the current line, if not all of
+    /// `attributes` are. See `parse_parallel`.
+    limit: Option<[usize; 3]>,
+    vertex_cache: HashMap<VertexKey, u32, FnvBuildHasher>,
+    vertices: Vec<Vec3<f32>>,
+    vertex_normals: Vec<Vec3<f32>>,
+    vertex_tex_coords: Vec<Vec3<f32>>,
+    vertex_colors: Vec<Vec4<f32>>,
+    /// For every vertex, the index of its position.
+    vertex_positions: Vec<usize>,
+    /// For every vertex, whether its normal has to be computed.
+    missing_normals: Vec<bool>,
+    indices: Vec<u32>,
+    edges: Vec<u32>,
+    lines: Vec<u32>,
+    points: Vec<u32>,
+    /// Smoothing group of every triangle.
+    smoothing_groups: Vec<u32>,
+    current_smoothing_group: u32,
+    has_smoothing_groups: bool,
+    materials: Vec<Material>,
+    material_ranges: Vec<MaterialRange>,
+    current_material: Option<usize>,
+    groups: Vec<Group>,
+    current_object: String,
+    warnings: Vec<ObjError>,
+    options: LoadOptions,
+    // Buffers reused from line to line, to save on allocations.
+    keys: Vec<VertexKey>,
+    element: Vec<u32>,
+    polygon: Vec<Vec3<f32>>,
+}
+
+impl Parser {
+    fn new(options: &LoadOptions) -> Parser {
+        Parser {
+            attributes: Attributes::new(),
+            limit: None,
+            vertex_cache: HashMap::default(),
+            vertices: Vec::new(),
+            vertex_normals: Vec::new(),
+            vertex_tex_coords: Vec::new(),
+            vertex_colors: Vec::new(),
+            vertex_positions: Vec::new(),
+            missing_normals: Vec::new(),
+            indices: Vec::new(),
+            edges: Vec::new(),
+            lines: Vec::new(),
+            points: Vec::new(),
+            smoothing_groups: Vec::new(),
+            // Faces before the first `s` are smoothed together.
+            current_smoothing_group: u32::MAX,
+            has_smoothing_groups: false,
+            materials: Vec::new(),
+            material_ranges: Vec::new(),
+            current_material: None,
+            groups: Vec::new(),
+            current_object: String::new(),
+            warnings: Vec::new(),
+            options: options.clone(),
+            keys: Vec::new(),
+            element: Vec::new(),
+            polygon: Vec::new(),
+        }
+    }
+
+    fn raw_line(&mut self, number: usize, bytes: &[u8], dir: &Path) -> Result<()> {
+        let text = match decode_line(bytes) {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+        let line = Line { number: number, text: &text };
+        if let Err(err) = self.line(line, dir) {
+            if !self.options.lenient {
+                return Err(err);
+            }
+            self.warnings.push(err);
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: Line, dir: &Path) -> Result<()> {
+        let mut words = line.text.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => return Ok(()),
+        };
+        if try!(self.attributes.line(keyword, line, line.text.split_whitespace().skip(1))) {
+            return Ok(());
+        }
+        match keyword {
+            "f" => {
+                try!(self.element(line, words, 3, "at least three vertices"));
+                self.face();
+            }
+            "l" => {
+                try!(self.element(line, words, 2, "at least two vertices"));
+                for segment in self.element.windows(2) {
+                    self.lines.extend_from_slice(segment);
+                }
+            }
+            "p" => {
+                try!(self.element(line, words, 1, "a vertex"));
+                self.points.extend_from_slice(&self.element);
+            }
+            "mtllib" => {
+                // Models often come without their materials, which are
+                // then drawn with the default one.
+                for name in words {
+                    let path = dir.join(name);
+                    match mtl::load_from_file(&path) {
+                        Ok(materials) => self.materials.extend(materials),
+                        Err(err) => {
+                            self.warnings.push(ObjError::MaterialLibrary {
+                                location: line.location(name),
+                                path: path,
+                                cause: Box::new(err),
+                            })
+                        }
+                    }
+                }
+            }
+            "usemtl" => {
+                // Without a name, or with one not in the library, faces go
+                // back to having no material.
+                let name = line.text.trim()["usemtl".len()..].trim();
+                self.current_material = if name.is_empty() {
+                    None
+                } else {
+                    self.materials.iter().rposition(|m| m.name == name)
+                };
+            }
+            "o" => {
+                self.current_object = line.text.trim()[1..].trim().to_owned();
+                let object = self.current_object.clone();
+                self.start_group(object, String::new());
+            }
+            "g" => {
+                let object = self.current_object.clone();
+                self.start_group(object, line.text.trim()[1..].trim().to_owned());
+            }
+            "s" => {
+                self.current_smoothing_group = try!(parse_smoothing_group(line, words));
+                self.has_smoothing_groups = true;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Adds the triangulated `self.element`.
+    fn face(&mut self) {
+        let start = self.indices.len();
+        if self.element.len() == 3 {
+            self.indices.extend_from_slice(&self.element);
+        } else {
+            self.polygon.clear();
+            for &i in &self.element {
+                self.polygon.push(self.vertices[i as usize]);
+            }
+            for triangle in triangulate(&self.polygon) {
+                for &i in &triangle {
+                    self.indices.push(self.element[i]);
+                }
+            }
+        }
+        let end = self.indices.len();
+        self.extend_material_range(start..end);
+        let count = (self.indices.len() - start) / 3;
+        for _ in 0..count {
+            self.smoothing_groups.push(self.current_smoothing_group);
+        }
+        if let Some(group) = self.groups.last_mut() {
+            group.range.end = self.indices.len();
+        }
+        let n = self.element.len();
+        for i in 0..n {
+            self.edges.push(self.element[i]);
+            self.edges.push(self.element[(i + 1) % n]);
+        }
+    }
+
+    /// Parses the vertices of a face, a polyline or a point set into
+    /// `self.element`, as indices of unified vertices.
+    fn element<'a, I>(&mut self, line: Line<'a>, args: I, min_count: usize, expected: &'static str) -> Result<()>
+        where I: Iterator<Item = &'a str>
+    {
+        // All vertices are checked before any is added.
+        self.keys.clear();
+        for token in args {
+            let raw = try!(parse_index(line, token));
+            let key = try!(self.resolve(line, token, raw));
+            self.keys.push(key);
+        }
+        if self.keys.len() < min_count {
+            return Err(line.syntax_error(line.end(), expected));
+        }
+
+        self.element.clear();
+        for i in 0..self.keys.len() {
+            let key = self.keys[i];
+            let index = try!(self.vertex(line, key));
+            self.element.push(index);
+        }
+        Ok(())
+    }
+
+    fn extend_material_range(&mut self, range: Range<usize>) {
+        if let Some(last) = self.material_ranges.last_mut() {
+            if last.material == self.current_material && last.range.end == range.start {
+                last.range.end = range.end;
+                return;
+            }
+        }
+        self.material_ranges.push(MaterialRange {
+            material: self.current_material,
+            range: range,
+        });
+    }
+
+    fn start_group(&mut self, object: String, name: String) {
+        let start = self.indices.len();
+        self.groups.push(Group {
+            object: object,
+            name: name,
+            range: start..start,
+        });
+    }
+
+    fn resolve(&self, line: Line, token: &str, raw: RawVertex) -> Result<VertexKey> {
+        let (position, tex_coord, normal) = raw;
+        let counts = self.limit.unwrap_or_else(|| self.attributes.counts());
+        let tex_coord = match tex_coord {
+            Some(i) => Some(try!(resolve_index(line, token, i, counts[1]))),
+            None => None,
+        };
+        let normal = match normal {
+            Some(i) => Some(try!(resolve_index(line, token, i, counts[2]))),
+            None => None,
+        };
+        Ok((try!(resolve_index(line, token, position, counts[0])), tex_coord, normal))
+    }
+
+    /// Returns the index of the unified vertex for the `key`, adding
+    /// a new one if this combination of attributes was not seen before.
+    fn vertex(&mut self, line: Line, key: VertexKey) -> Result<u32> {
+        if let Some(&index) = self.vertex_cache.get(&key) {
+            return Ok(index);
+        }
+
+        let (position_id, tex_coord, normal) = key;
+        let position = self.attributes.positions[position_id];
+        let tex_coord = match tex_coord {
+            Some(tex_coord) => self.attributes.tex_coords[tex_coord],
+            None => Vec3::new(0.0, 0.0, 0.0),
+        };
+        let normal = match normal {
+            Some(normal) => self.attributes.normals[normal],
+            None => Vec3::new(0.0, 0.0, 0.0),
+        };
+
+        if self.vertices.len() > u32::MAX as usize {
+            return Err(ObjError::NotSupported {
+                location: line.location(line.text),
+                feature: "more than 2^32 vertices",
+            });
+        }
+        let index = self.vertices.len() as u32;
+        self.vertices.push(position);
+        self.vertex_normals.push(normal);
+        self.vertex_tex_coords.push(tex_coord);
+        self.vertex_colors.push(self.attributes.position_colors[position_id]);
+        self.vertex_positions.push(position_id);
+        self.missing_normals.push(key.2.is_none());
+        self.vertex_cache.insert(key, index);
+        Ok(index)
+    }
 
-    for line in data.lines() {
-        if line.starts_with("v ") {
-            result.vertices.push(Vertex::from(try!(parse_vec(line))))
-        } else if line.starts_with("vn ") {
-            result.normals.push(Normal::from(try!(parse_vec(line))))
-        } else if line.starts_with("f ") {
-            result.indices.extend(try!(parse_face(line)).iter())
+    fn finish(mut self) -> Result<Obj> {
+        if self.missing_normals.iter().any(|&m| m) {
+            let smoothing_groups: &[u32] = if self.has_smoothing_groups {
+                &self.smoothing_groups
+            } else {
+                &[]
+            };
+            let splits = normals::generate(self.options.normals,
+                                           &self.vertices,
+                                           &self.vertex_positions,
+                                           &self.missing_normals,
+                                           smoothing_groups,
+                                           &mut self.indices,
+                                           &mut self.vertex_normals);
+            for v in splits {
+                let v = v as usize;
+                let (position, tex_coord, color) = (self.vertices[v],
+                                                    self.vertex_tex_coords[v],
+                                                    self.vertex_colors[v]);
+                self.vertices.push(position);
+                self.vertex_tex_coords.push(tex_coord);
+                self.vertex_colors.push(color);
+            }
+        }
+
+        let vertex_count = self.vertices.len();
+        try!(check_buffers(&self.indices, &self.edges, &self.lines, &self.points, vertex_count));
+        Ok(Obj {
+            vertices: self.vertices.into_iter().map(Vertex::from).collect(),
+            normals: self.vertex_normals.into_iter().map(Normal::from).collect(),
+            tex_coords: self.vertex_tex_coords.into_iter().map(TexCoord::from).collect(),
+            colors: if self.attributes.has_colors {
+                self.vertex_colors.into_iter().map(Color::from).collect()
+            } else {
+                Vec::new()
+            },
+            indices: Indices::new(self.indices, vertex_count),
+            edges: Indices::new(self.edges, vertex_count),
+            lines: Indices::new(self.lines, vertex_count),
+            points: Indices::new(self.points, vertex_count),
+            materials: self.materials,
+            material_ranges: self.material_ranges,
+            groups: self.groups.into_iter().filter(|g| g.range.start != g.range.end).collect(),
+            warnings: self.warnings,
+        })
+    }
+}
+
+/// Strips the line terminator and decodes the line, unless it is a comment.
+/// Comments are skipped before decoding, as they often are in some legacy encoding.
+fn decode_line<'a>(bytes: &'a [u8]) -> Option<Cow<'a, str>> {
+    let bytes = strip_terminator(bytes);
+    if is_comment(bytes) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(bytes))
+}
+
+fn strip_terminator(bytes: &[u8]) -> &[u8] {
+    let mut bytes = bytes;
+    while let Some((&last, rest)) = bytes.split_last() {
+        if last != b'\n' && last != b'\r' {
+            break;
+        }
+        bytes = rest;
+    }
+    bytes
+}
+
+fn is_comment(bytes: &[u8]) -> bool {
+    bytes.iter().find(|&&b| b != b' ' && b != b'\t') == Some(&b'#')
+}
+
+/// If the line ends with a `\`, which joins it with the next one, returns
+/// its length without the `\`. Comments are never continued.
+fn continued(bytes: &[u8]) -> Option<usize> {
+    let bytes = strip_terminator(bytes);
+    if bytes.last() != Some(&b'\\') || is_comment(bytes) {
+        return None;
+    }
+    Some(bytes.len() - 1)
+}
+
+/// Splits the data into lines, and joins those continued with a `\` into
+/// one, separated by spaces. Yields the number of the first of them along
+/// with the text.
+struct JoinedLines<'a> {
+    rest: Option<&'a [u8]>,
+    /// Number of lines split off so far.
+    count: usize,
+}
+
+impl<'a> JoinedLines<'a> {
+    fn new(data: &'a [u8]) -> JoinedLines<'a> {
+        JoinedLines { rest: Some(data), count: 0 }
+    }
+
+    fn next_line(&mut self) -> Option<&'a [u8]> {
+        let rest = match self.rest {
+            Some(rest) => rest,
+            None => return None,
+        };
+        self.count += 1;
+        match rest.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                self.rest = Some(&rest[end + 1..]);
+                Some(&rest[..end])
+            }
+            None => {
+                self.rest = None;
+                Some(rest)
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for JoinedLines<'a> {
+    type Item = (usize, Cow<'a, [u8]>);
+
+    fn next(&mut self) -> Option<(usize, Cow<'a, [u8]>)> {
+        let number = self.count + 1;
+        let mut joined = Vec::new();
+        while let Some(line) = self.next_line() {
+            match continued(line) {
+                Some(end) => {
+                    joined.extend_from_slice(&line[..end]);
+                    joined.push(b' ');
+                }
+                None if joined.is_empty() => return Some((number, Cow::Borrowed(line))),
+                None => {
+                    joined.extend_from_slice(line);
+                    break;
+                }
+            }
+        }
+        if joined.is_empty() {
+            None
+        } else {
+            Some((number, Cow::Owned(joined)))
+        }
+    }
+}
+
+/// Vertex attributes parsed by a worker thread, and the lines it left
+/// for the main thread.
+struct Chunk<'a> {
+    attributes: Attributes,
+    /// Lines which depend on the state of the parser, with their number
+    /// and the counts of attributes defined before them, within the chunk.
+    deferred: Vec<(usize, Cow<'a, [u8]>, [usize; 3])>,
+    line_count: usize,
+    warnings: Vec<ObjError>,
+    error: Option<ObjError>,
+}
+
+/// Parses the bulk of the file, the `v`, `vn` and `vt` lines, on `options.threads`
+/// threads. The data is split into chunks at line boundaries, and the rest of the
+/// lines are replayed in order once all the attributes are known, as if they
+/// were parsed one by one.
+fn parse_parallel(data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj> {
+    let chunks = split_lines(data, options.threads);
+    let lenient = options.lenient;
+    let chunks = crossbeam::scope(|scope| {
+        let handles = chunks.into_iter()
+                            .map(|data| scope.spawn(move || parse_chunk(data, lenient)))
+                            .collect::<Vec<_>>();
+        handles.into_iter().map(|handle| handle.join()).collect::<Vec<_>>()
+    });
+
+    let mut parser = Parser::new(options);
+    let mut first_line = 0;
+    for mut chunk in chunks {
+        let base = parser.attributes.counts();
+        parser.attributes.append(&mut chunk.attributes);
+        for (number, bytes, counts) in chunk.deferred {
+            parser.limit = Some([base[0] + counts[0], base[1] + counts[1], base[2] + counts[2]]);
+            try!(parser.raw_line(first_line + number, &bytes, dir));
+        }
+        for mut warning in chunk.warnings {
+            warning.offset_line(first_line);
+            parser.warnings.push(warning);
+        }
+        if let Some(mut err) = chunk.error {
+            err.offset_line(first_line);
+            return Err(err);
+        }
+        first_line += chunk.line_count;
+    }
+    parser.limit = None;
+    parser.warnings.sort_by_key(|warning| warning.location().map_or(0, |location| location.line));
+    parser.finish()
+}
+
+/// Splits the data into about `count` chunks of whole lines, keeping lines
+/// continued with a `\` together with the next one.
+fn split_lines(data: &[u8], count: usize) -> Vec<&[u8]> {
+    let mut chunks = Vec::with_capacity(count);
+    let mut rest = data;
+    for remaining in (2..count + 1).rev() {
+        let size = rest.len() / remaining;
+        let found = {
+            let line_end = |end: usize| {
+                let start = rest[..end].iter().rposition(|&b| b == b'\n').map_or(0, |start| start + 1);
+                rest[end] == b'\n' && continued(&rest[start..end]).is_none()
+            };
+            (size..rest.len()).find(|&end| line_end(end))
+        };
+        match found {
+            Some(end) => {
+                let (chunk, tail) = rest.split_at(end + 1);
+                chunks.push(chunk);
+                rest = tail;
+            }
+            None => break,
+        }
+    }
+    chunks.push(rest);
+    chunks
+}
+
+fn parse_chunk<'a>(data: &'a [u8], lenient: bool) -> Chunk<'a> {
+    // Every chunk but the last ends with a newline, which does not start another line.
+    let data = match data.split_last() {
+        Some((&b'\n', rest)) => rest,
+        _ => data,
+    };
+    let mut chunk = Chunk {
+        attributes: Attributes::new(),
+        deferred: Vec::new(),
+        line_count: 0,
+        warnings: Vec::new(),
+        error: None,
+    };
+    let mut lines = JoinedLines::new(data);
+    for (number, bytes) in lines.by_ref() {
+        let text = match decode_line(&bytes) {
+            Some(text) => text,
+            None => continue,
+        };
+        let line = Line { number: number, text: &text };
+        let mut words = text.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match chunk.attributes.line(keyword, line, words) {
+            Ok(true) => (),
+            Ok(false) => {
+                let counts = chunk.attributes.counts();
+                chunk.deferred.push((number, bytes.clone(), counts));
+            }
+            Err(err) => {
+                if !lenient {
+                    chunk.error = Some(err);
+                    break;
+                }
+                chunk.warnings.push(err);
+            }
         }
     }
+    chunk.line_count = lines.count;
+    chunk
+}
+
+/// Parses `v x y z [w]`, or `v x y z r g b [a]` with a vertex color.
+/// The weight `w` only matters for rational curves and is ignored.
+fn parse_position<'a, I: Iterator<Item = &'a str>>(line: Line<'a>, args: I) -> Result<(Vec3<f32>, Option<Vec4<f32>>)> {
+    let expected = "coordinates, optionally followed by a color";
+    let mut coords = [0.0; 7];
+    let count = try!(parse_floats(line, args, &mut coords, expected));
 
-    Ok(result)
+    let position = Vec3::new(coords[0], coords[1], coords[2]);
+    match count {
+        3 => Ok((position, None)),
+        4 => Ok((position, None)),
+        6 => Ok((position, Some(Vec4::new(coords[3], coords[4], coords[5], 1.0)))),
+        7 => Ok((position, Some(Vec4::new(coords[3], coords[4], coords[5], coords[6])))),
+        _ => Err(line.syntax_error(line.end(), expected)),
+    }
 }
 
-fn parse_vec(line: &str) -> Result<Vec3<f32>> {
-    let coords = try!(line.split_whitespace()
-                          .skip(1)
-                          .map(|s| s.parse::<f32>().map_err(|_| ObjError::SyntaxError))
-                          .collect::<Result<Vec<_>>>());
+/// Parses exactly three coordinates.
+pub fn parse_vec<'a, I: Iterator<Item = &'a str>>(line: Line<'a>, args: I) -> Result<Vec3<f32>> {
+    let mut coords = [0.0; 3];
+    if try!(parse_floats(line, args, &mut coords, "three coordinates")) != 3 {
+        return Err(line.syntax_error(line.end(), "three coordinates"));
+    }
+
+    Ok(Vec3::new(coords[0], coords[1], coords[2]))
+}
 
-    if coords.len() != 3 {
-        return Err(ObjError::SyntaxError);
+/// Parses `vt u [v [w]]`.
+fn parse_tex_coord<'a, I: Iterator<Item = &'a str>>(line: Line<'a>, args: I) -> Result<Vec3<f32>> {
+    let expected = "one to three texture coordinates";
+    let mut coords = [0.0; 3];
+    if try!(parse_floats(line, args, &mut coords, expected)) == 0 {
+        return Err(line.syntax_error(line.end(), expected));
     }
 
     Ok(Vec3::new(coords[0], coords[1], coords[2]))
 }
 
-fn parse_face(line: &str) -> Result<Vec<u16>> {
-    let verts = try!(line.split_whitespace()
-                         .skip(1)
-                         .map(parse_index)
-                         .collect::<Result<Vec<_>>>());
+/// Parses numbers into `out`, returns how many there were. Reports
+/// `expected` if there are more of them than fit.
+fn parse_floats<'a, I>(line: Line<'a>, args: I, out: &mut [f32], expected: &'static str) -> Result<usize>
+    where I: Iterator<Item = &'a str>
+{
+    let mut count = 0;
+    for token in args {
+        if count == out.len() {
+            return Err(line.syntax_error(token, expected));
+        }
+        out[count] = try!(float::parse(token).ok_or_else(|| line.syntax_error(token, "a number")));
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Parses `s N` or `s off`, which is the same as `s 0`.
+fn parse_smoothing_group<'a, I: Iterator<Item = &'a str>>(line: Line<'a>, mut args: I) -> Result<u32> {
+    let group = try!(args.next().ok_or_else(|| line.syntax_error(line.end(), "a single smoothing group")));
+    if let Some(token) = args.next() {
+        return Err(line.syntax_error(token, "a single smoothing group"));
+    }
+    if group == "off" {
+        return Ok(0);
+    }
+    group.parse().map_err(|_| line.syntax_error(group, "a smoothing group number or `off`"))
+}
+
+/// Parses one of the `v`, `v/vt`, `v//vn` or `v/vt/vn` forms.
+fn parse_index(line: Line, s: &str) -> Result<RawVertex> {
+    let mut parts = s.split('/');
+    let position = try!(parse_one_index(line, parts.next().unwrap_or(s)));
+    let tex_coord = parts.next();
+    let normal = parts.next();
+    if parts.next().is_some() {
+        return Err(line.syntax_error(s, "a vertex like `v`, `v/vt`, `v//vn` or `v/vt/vn`"));
+    }
+    let tex_coord = match tex_coord {
+        Some(t) if !t.is_empty() || normal.is_none() => Some(try!(parse_one_index(line, t))),
+        _ => None,
+    };
+    let normal = match normal {
+        Some(n) => Some(try!(parse_one_index(line, n))),
+        None => None,
+    };
+    Ok((position, tex_coord, normal))
+}
 
-    if verts.len() != 3 {
-        return Err(ObjError::SyntaxError);
+fn parse_one_index(line: Line, s: &str) -> Result<i64> {
+    match s.parse::<i64>() {
+        Ok(0) | Err(_) => Err(line.syntax_error(s, "a non-zero index")),
+        Ok(i) => Ok(i),
     }
+}
 
-    Ok(verts)
+/// Converts a one-based or a negative relative index into a zero-based one,
+/// given the number of elements defined so far.
+fn resolve_index(line: Line, token: &str, index: i64, count: usize) -> Result<usize> {
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        count as i64 + index
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(ObjError::IndexOutOfRange {
+            location: line.location(token),
+            index: index,
+            count: count,
+        });
+    }
+    Ok(resolved as usize)
 }
 
-fn parse_index(s: &str) -> Result<u16> {
-    let inds = try!(s.split("//")
-                     .map(|i| {
-                         i.parse::<u16>()
-                          .map(|i| i - 1)
-                          .map_err(|_| ObjError::SyntaxError)
-                     })
-                     .collect::<Result<Vec<_>>>());
-    if inds.len() != 2 {
-        return Err(ObjError::SyntaxError);
+
+impl fmt::Display for Location {
+    /// Renders the offending line, with the token underlined.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = self.line.to_string();
+        let gutter = number.chars().map(|_| ' ').collect::<String>();
+        let indent = self.source
+                         .chars()
+                         .take(self.column - 1)
+                         .map(|c| if c == '\t' { '\t' } else { ' ' })
+                         .collect::<String>();
+        let marker = (0..cmp::max(1, self.token.chars().count())).map(|_| '^').collect::<String>();
+        try!(writeln!(f, "{} |", gutter));
+        try!(writeln!(f, "{} | {}", number, self.source));
+        write!(f, "{} | {}{}", gutter, indent, marker)
     }
-    if inds[0] != inds[1] {
-        return Err(ObjError::NotSupported);
+}
+
+impl Location {
+    fn found(&self) -> String {
+        if self.token.is_empty() {
+            "end of line".to_owned()
+        } else {
+            format!("`{}`", self.token)
+        }
     }
-    Ok(inds[0])
 }
 
+impl ObjError {
+    /// Where in the source the error is, unless it is not about a particular line.
+    pub fn location(&self) -> Option<&Location> {
+        match *self {
+            ObjError::SyntaxError { ref location, .. } |
+            ObjError::NotSupported { ref location, .. } |
+            ObjError::IndexOutOfRange { ref location, .. } |
+            ObjError::MaterialLibrary { ref location, .. } => Some(location),
+            ObjError::Io(_) | ObjError::InvalidIndex { .. } | ObjError::Malformed(_) => None,
+        }
+    }
+
+    /// Turns a line number within a chunk into one within the file,
+    /// given the number of lines before the chunk.
+    fn offset_line(&mut self, offset: usize) {
+        match *self {
+            ObjError::SyntaxError { ref mut location, .. } |
+            ObjError::NotSupported { ref mut location, .. } |
+            ObjError::IndexOutOfRange { ref mut location, .. } |
+            ObjError::MaterialLibrary { ref mut location, .. } => location.line += offset,
+            ObjError::Io(_) | ObjError::InvalidIndex { .. } | ObjError::Malformed(_) => (),
+        }
+    }
+}
 
 impl fmt::Display for ObjError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ObjError::Io(ref err) => write!(f, "IO error: {}", err),
-            ObjError::SyntaxError => write!(f, "Syntax Error"),
-            ObjError::NotSupported => write!(f, "Feature not supported"),
+            ObjError::SyntaxError { ref location, expected } =>
+                write!(f, "Syntax error at line {}, column {}: expected {}, found {}\n{}",
+                       location.line, location.column, expected, location.found(), location),
+            ObjError::NotSupported { ref location, feature } =>
+                write!(f, "Not supported at line {}, column {}: {}\n{}",
+                       location.line, location.column, feature, location),
+            ObjError::IndexOutOfRange { ref location, index, count } =>
+                write!(f, "Index out of range at line {}, column {}: {} is used, but only {} elements are defined\n{}",
+                       location.line, location.column, index, count, location),
+            ObjError::InvalidIndex { primitive, number, index, count } =>
+                write!(f, "Invalid index in {} {}: vertex {} is used, but only {} vertices are defined",
+                       primitive, number, index, count),
+            ObjError::Malformed(ref message) => write!(f, "Malformed model: {}", message),
+            ObjError::MaterialLibrary { ref location, ref path, ref cause } =>
+                write!(f, "Could not load the material library {} named at line {}, column {}: {}",
+                       path.display(), location.line, location.column, cause),
         }
     }
 }
@@ -134,16 +1349,24 @@ impl error::Error for ObjError {
     fn description(&self) -> &str {
         match *self {
             ObjError::Io(ref err) => err.description(),
-            ObjError::SyntaxError => "Syntax Error",
-            ObjError::NotSupported => "Feature not supported",
+            ObjError::SyntaxError { .. } => "Syntax error",
+            ObjError::NotSupported { .. } => "Feature not supported",
+            ObjError::IndexOutOfRange { .. } => "Index out of range",
+            ObjError::InvalidIndex { .. } => "Invalid index",
+            ObjError::Malformed(_) => "Malformed model",
+            ObjError::MaterialLibrary { .. } => "Could not load a material library",
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             ObjError::Io(ref err) => Some(err),
-            ObjError::SyntaxError => None,
-            ObjError::NotSupported => None,
+            ObjError::SyntaxError { .. } => None,
+            ObjError::NotSupported { .. } => None,
+            ObjError::IndexOutOfRange { .. } => None,
+            ObjError::InvalidIndex { .. } => None,
+            ObjError::Malformed(_) => None,
+            ObjError::MaterialLibrary { ref cause, .. } => Some(&**cause),
         }
     }
 }
@@ -153,3 +1376,369 @@ impl From<io::Error> for ObjError {
         ObjError::Io(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+
+    use na::{self, Vec3, Vec4};
+
+    use mtl::Material;
+    use super::*;
+    use super::split_lines;
+
+    fn indices(indices: &Indices) -> Vec<u32> {
+        (0..indices.len()).map(|i| indices.get(i)).collect()
+    }
+
+    fn parse(source: &str) -> Obj {
+        parse_bytes(source.as_bytes(), Path::new(""), &LoadOptions::default()).unwrap()
+    }
+
+    fn positions(obj: &Obj) -> Vec<Vec3<f32>> {
+        obj.vertices.iter().map(|v| v.position).collect()
+    }
+
+    fn normals(obj: &Obj) -> Vec<Vec3<f32>> {
+        obj.normals.iter().map(|n| n.normal).collect()
+    }
+
+    fn tex_coords(obj: &Obj) -> Vec<Vec3<f32>> {
+        obj.tex_coords.iter().map(|t| t.tex_coord).collect()
+    }
+
+    const TRIANGLE: &'static str = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 1\n";
+
+    #[test]
+    fn face_forms() {
+        let obj = parse(&format!("{}f 1 2 3\nf 1/1 2/2 3/3\nf 1//1 2//1 3//1\nf 1/1/1 2/2/1 3/3/1\n", TRIANGLE));
+        // The forms refer to different attributes, so no vertex is shared.
+        assert_eq!(indices(&obj.indices), (0..12).collect::<Vec<_>>());
+        let corners = vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+        assert_eq!(positions(&obj), [&corners[..], &corners[..], &corners[..], &corners[..]].concat());
+        let none = vec![Vec3::new(0.0, 0.0, 0.0); 3];
+        assert_eq!(tex_coords(&obj), [&none[..], &corners[..], &none[..], &corners[..]].concat());
+        // Missing normals are generated, and match the given ones here.
+        assert_eq!(normals(&obj), vec![Vec3::new(0.0, 0.0, 1.0); 12]);
+    }
+
+    #[test]
+    fn face_form_errors() {
+        for face in &["f 1/1/1/1 2 3", "f 1/ 2 3", "f 1//x 2 3", "f 0 1 2", "f 1 2"] {
+            let source = format!("{}{}\n", TRIANGLE, face);
+            match parse_bytes(source.as_bytes(), Path::new(""), &LoadOptions::default()) {
+                Err(ObjError::SyntaxError { location, .. }) => assert_eq!(location.line, 8),
+                other => panic!("expected a syntax error for `{}`, got {:?}", face, other),
+            }
+        }
+    }
+
+    #[test]
+    fn shared_vertices() {
+        let obj = parse(&format!("{}v 1 1 0\nf 1//1 2//1 3//1\nf 2//1 4//1 3//1\nf 1/1/1 2//1 3//1\n", TRIANGLE));
+        // Equal position, texture coordinate and normal indices make one vertex.
+        assert_eq!(indices(&obj.indices), [0, 1, 2, 1, 3, 2, 4, 1, 2]);
+        assert_eq!(positions(&obj),
+                   [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+                    Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 0.0)]);
+    }
+
+    /// Three triangles, each in its own group and with its own material
+    /// or none, then a line and a point. The group without an object comes
+    /// first, as it would not be read back after the others. The vertices are numbered in the
+    /// order they are used, as the parser numbers them.
+    fn model() -> Obj {
+        let mut mesh = IndexedMesh::default();
+        for i in 0..12 {
+            let x = i as f32;
+            mesh.positions.push(Vec3::new(x, x * 0.5, -x));
+            mesh.normals.push(Vec3::new(0.0, 0.0, 1.0));
+            mesh.tex_coords.push(Vec3::new(x * 0.25, 1.0 - x * 0.125, if i % 2 == 0 { 0.0 } else { 0.5 }));
+            mesh.colors.push(Vec4::new(x / 16.0, 0.5, 1.0, 1.0));
+        }
+        for t in 0..3 {
+            mesh.add_polygon(&[3 * t, 3 * t + 1, 3 * t + 2]);
+        }
+        mesh.lines = vec![9, 10];
+        mesh.points = vec![11];
+        mesh.materials = vec![Material::new("red"), Material::new("blue")];
+        mesh.material_ranges = vec![MaterialRange { material: Some(0), range: 0..3 },
+                                    MaterialRange { material: None, range: 3..6 },
+                                    MaterialRange { material: Some(1), range: 6..9 }];
+        mesh.groups = vec![Group { object: String::new(), name: "loose".to_owned(), range: 0..3 },
+                           Group { object: "box".to_owned(), name: String::new(), range: 3..6 },
+                           Group { object: "box".to_owned(), name: "lid".to_owned(), range: 6..9 }];
+        mesh.into_obj(&LoadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn negative_indices() {
+        let absolute = parse(&format!("{}f 1/1/1 2/2/1 3/3/1\n", TRIANGLE));
+        let relative = parse(&format!("{}f -3/-3/-1 -2/-2/-1 -1/-1/-1\n", TRIANGLE));
+        assert_eq!(format!("{:?}", relative), format!("{:?}", absolute));
+
+        // Relative to the vertices defined before the face, not in the whole file.
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 5 0 0\nv 6 0 0\nv 5 1 0\nf -3 -2 -1\n");
+        assert_eq!(positions(&obj)[3..], [Vec3::new(5.0, 0.0, 0.0), Vec3::new(6.0, 0.0, 0.0), Vec3::new(5.0, 1.0, 0.0)]);
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        for &(face, token, index, count) in &[("f -4 -2 -1", "-4", -4, 3),
+                                              ("f 1 2 4", "4", 4, 3),
+                                              ("f 1/4 2/1 3/1", "1/4", 4, 3),
+                                              ("f 1//-2 2//1 3//1", "1//-2", -2, 1)] {
+            let source = format!("{}{}\n", TRIANGLE, face);
+            match parse_bytes(source.as_bytes(), Path::new(""), &LoadOptions::default()) {
+                Err(ObjError::IndexOutOfRange { location, index: i, count: c }) => {
+                    assert_eq!((location.line, &location.token[..], i, c), (8, token, index, count))
+                }
+                other => panic!("expected an index out of range for `{}`, got {:?}", face, other),
+            }
+        }
+    }
+
+    #[test]
+    fn error_location() {
+        let source = "v 0 0 0\n# comment\n\tv 1 \u{e9} 0\n";
+        let err = parse_bytes(source.as_bytes(), Path::new(""), &LoadOptions::default()).unwrap_err();
+        {
+            let location = err.location().unwrap();
+            // Columns count characters, and the tab before the keyword is one.
+            assert_eq!((location.line, location.column, &location.token[..]), (3, 6, "\u{e9}"));
+            assert_eq!(location.source, "\tv 1 \u{e9} 0");
+        }
+        assert_eq!(err.to_string(),
+                   "Syntax error at line 3, column 6: expected a number, found `\u{e9}`\n  |\n3 | \tv 1 \u{e9} 0\n  | \t    ^");
+
+        let err = parse_bytes(b"v 0 0 0\r\nv 1 0 0\r\nf 1 2\r\n", Path::new(""), &LoadOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Syntax error at line 3, column 6: expected at least three vertices, found end of line\n  |\n3 | f 1 2\n  |      ^");
+    }
+
+    #[test]
+    fn lenient_load() {
+        let source = format!("{}v 1 x 0\nf 1 2 3\nf 1 2 9\ns maybe\nf 1 2 3\n", TRIANGLE);
+        assert!(parse_bytes(source.as_bytes(), Path::new(""), &LoadOptions::default()).is_err());
+
+        let options = LoadOptions { lenient: true, ..LoadOptions::default() };
+        let obj = parse_bytes(source.as_bytes(), Path::new(""), &options).unwrap();
+        assert_eq!(indices(&obj.indices), [0, 1, 2, 0, 1, 2]);
+        let lines = obj.warnings.iter().map(|w| w.location().unwrap().line).collect::<Vec<_>>();
+        assert_eq!(lines, [8, 10, 11]);
+    }
+
+    /// Two faces meeting at a right angle along the edge from vertex 1 to 2.
+    fn corner(first: &str, second: &str) -> Obj {
+        parse(&format!("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n{}\nf 1 2 3\n{}\nf 1 4 2\n", first, second))
+    }
+
+    #[test]
+    fn smoothing_groups() {
+        let (up, side) = (Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 1.0, 0.0));
+        let half = 0.5f32.sqrt();
+        let between = Vec3::new(0.0, half, half);
+        let close = |a: &[Vec3<f32>], b: &[Vec3<f32>]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| na::norm(&(*a - *b)) < 1e-6)
+        };
+
+        // Within a group, and without any, the shared edge is smooth.
+        for &(first, second) in &[("s 1", "s 1"), ("", "")] {
+            let obj = corner(first, second);
+            assert_eq!(indices(&obj.indices), [0, 1, 2, 0, 3, 1]);
+            assert!(close(&normals(&obj), &[between, between, up, side]), "{:?}", normals(&obj));
+        }
+        // Across groups, and in group zero, the edge is hard and its vertices are split.
+        for &(first, second) in &[("s 1", "s 2"), ("s off", "s off"), ("s 0", "s 1")] {
+            let obj = corner(first, second);
+            assert_eq!(obj.vertices.len(), 6);
+            let corners = indices(&obj.indices).iter().map(|&i| obj.normals[i as usize].normal).collect::<Vec<_>>();
+            assert!(close(&corners, &[up, up, up, side, side, side]), "{:?}", corners);
+        }
+    }
+
+    #[test]
+    fn lines_and_points() {
+        let obj = parse(&format!("{}l 1 2 3 1\nl 1/1 2/2\np 3 -1\nf 1 2 3\n", TRIANGLE));
+        // Polylines become segments, sharing vertices with faces of the same form.
+        assert_eq!(indices(&obj.lines), [0, 1, 1, 2, 2, 0, 3, 4]);
+        assert_eq!(indices(&obj.points), [2, 2]);
+        assert_eq!(indices(&obj.indices), [0, 1, 2]);
+        assert_eq!(tex_coords(&obj)[3..5], [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)]);
+        assert!(obj.edges.len() == 6 && obj.material_ranges.len() == 1);
+
+        for element in &["l 1", "p", "l 1 4"] {
+            let source = format!("{}{}\n", TRIANGLE, element);
+            assert!(parse_bytes(source.as_bytes(), Path::new(""), &LoadOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn polygons_keep_their_outline() {
+        let obj = parse("v 0 0 0\nv 4 0 0\nv 4 4 0\nv 2 1 0\nv 0 4 0\nf 1 2 3 4 5\nf 1 2 3\n");
+        assert_eq!(obj.indices.len(), 3 * 3 + 3);
+        // The edges follow the polygons as written, not the triangles.
+        assert_eq!(indices(&obj.edges), [0, 1, 1, 2, 2, 3, 3, 4, 4, 0, 0, 1, 1, 2, 2, 0]);
+        let material_ranges = obj.material_ranges.iter().map(|r| r.range.clone()).collect::<Vec<_>>();
+        assert_eq!(material_ranges, [0..12]);
+    }
+
+    fn parse_with_threads(source: &str, threads: usize) -> Result<Obj> {
+        let options = LoadOptions { threads: threads, ..LoadOptions::default() };
+        parse_bytes(source.as_bytes(), Path::new(""), &options)
+    }
+
+    /// A strip of quads with Windows line endings, each one using the
+    /// positions of the previous one through negative indices.
+    fn strip(length: usize) -> String {
+        let mut source = "vn 0 0 1\r\nv 0 0 0\r\nv 0 1 0\r\n".to_owned();
+        for i in 1..length + 1 {
+            source.push_str(&format!("v {} 0 0\r\nv {} 1 0\r\nf -4//-1 -2//-1 -1//-1 -3//-1\r\n", i, i));
+        }
+        source
+    }
+
+    #[test]
+    fn parallel_parse() {
+        let source = strip(50);
+        let serial = parse_with_threads(&source, 1).unwrap();
+        assert_eq!((serial.vertices.len(), serial.indices.len()), (102, 300));
+        assert_eq!(positions(&serial)[100..], [Vec3::new(50.0, 0.0, 0.0), Vec3::new(50.0, 1.0, 0.0)]);
+        for threads in 2..9 {
+            let parallel = parse_with_threads(&source, threads).unwrap();
+            assert_eq!(format!("{:?}", parallel), format!("{:?}", serial));
+        }
+
+        // Errors point to the same line, whichever chunk they are in.
+        let source = format!("{}f 1 2 -200\r\n{}", strip(10), strip(10));
+        for threads in 1..5 {
+            let err = parse_with_threads(&source, threads).unwrap_err();
+            assert_eq!(err.location().unwrap().line, 34);
+        }
+    }
+
+    #[test]
+    fn chunks_end_with_lines() {
+        // Both splits land right on a newline.
+        let source = "v 0 0 0\nv 1 0 0\np 1 2\n";
+        let data = source.as_bytes();
+        assert_eq!(split_lines(data, 3), [&data[..8], &data[8..16], &data[16..]]);
+        let obj = parse_with_threads(source, 3).unwrap();
+        assert_eq!(positions(&obj), [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)]);
+
+        let data = b"v 0 0 \\\n0\nv 1 0 0\n";
+        for count in 1..data.len() {
+            let chunks = split_lines(data, count);
+            assert!(chunks.iter().all(|chunk| !chunk.ends_with(b"\\\n")), "{:?}", chunks);
+            assert_eq!(chunks.concat(), &data[..]);
+        }
+    }
+
+    #[test]
+    fn continued_lines() {
+        let source = "v 0 0 \\\n0\nv 1 \\\r\n0 \\\n0\n# a comment \\\nv 0 1 0\nf 1 \\\n2 3\n";
+        for threads in 1..6 {
+            let obj = parse_with_threads(source, threads).unwrap();
+            assert_eq!(positions(&obj), [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)]);
+            assert_eq!(indices(&obj.indices), [0, 1, 2]);
+        }
+        let obj = parse_reader(source.as_bytes(), Path::new(""), &LoadOptions::default()).unwrap();
+        assert_eq!(indices(&obj.indices), [0, 1, 2]);
+
+        // Errors point to the first of the lines, and those after them keep their numbers.
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 \\\n2 \\\n3\nf 1 2 4\nf 1 \\\n5 2\n";
+        for &(ref text, line) in &[(source.to_owned(), 7), (source.replace("f 1 2 4", "f 1 2 3"), 8)] {
+            for threads in 1..4 {
+                let err = parse_with_threads(text, threads).unwrap_err();
+                assert_eq!(err.location().unwrap().line, line);
+            }
+            let err = parse_reader(text.as_bytes(), Path::new(""), &LoadOptions::default()).unwrap_err();
+            assert_eq!(err.location().unwrap().line, line);
+        }
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let obj = model();
+        let dir = env::temp_dir().join("bunny-obj-write-round-trip");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.obj");
+        save_to_file(&obj, &path, &WriteOptions::default()).unwrap();
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let parsed = parse_bytes(&data, &dir, &LoadOptions::default());
+        fs::remove_dir_all(&dir).unwrap();
+        let parsed = parsed.unwrap();
+
+        assert!(parsed.warnings.is_empty());
+        let positions = |obj: &Obj| obj.vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+        let normals = |obj: &Obj| obj.normals.iter().map(|n| n.normal).collect::<Vec<_>>();
+        let tex_coords = |obj: &Obj| obj.tex_coords.iter().map(|t| t.tex_coord).collect::<Vec<_>>();
+        let colors = |obj: &Obj| obj.colors.iter().map(|c| c.color).collect::<Vec<_>>();
+        assert_eq!(positions(&parsed), positions(&obj));
+        assert_eq!(normals(&parsed), normals(&obj));
+        assert_eq!(tex_coords(&parsed), tex_coords(&obj));
+        assert_eq!(colors(&parsed), colors(&obj));
+        assert_eq!(indices(&parsed.indices), indices(&obj.indices));
+        assert_eq!(indices(&parsed.edges), indices(&obj.edges));
+        assert_eq!(indices(&parsed.lines), indices(&obj.lines));
+        assert_eq!(indices(&parsed.points), indices(&obj.points));
+
+        let groups = |obj: &Obj| {
+            obj.groups.iter().map(|g| (g.object.clone(), g.name.clone(), g.range.clone())).collect::<Vec<_>>()
+        };
+        assert_eq!(groups(&parsed), groups(&obj));
+        let materials = |obj: &Obj| {
+            obj.material_ranges
+               .iter()
+               .map(|r| (r.material.map(|m| obj.materials[m].name.clone()), r.range.clone()))
+               .collect::<Vec<_>>()
+        };
+        assert_eq!(materials(&parsed), materials(&obj));
+    }
+
+    #[test]
+    fn index_past_u16_range() {
+        let mut mesh = IndexedMesh::default();
+        mesh.positions = vec![Vec3::new(0.0, 0.0, 0.0); 3];
+        mesh.indices = vec![0, 1, 65536 + 2];
+        match mesh.into_obj(&LoadOptions::default()) {
+            Err(ObjError::InvalidIndex { primitive: "face", number: 0, index: 65538, count: 3 }) => (),
+            other => panic!("expected an invalid index, got {:?}", other),
+        }
+
+        let mut mesh = IndexedMesh::default();
+        mesh.positions = vec![Vec3::new(0.0, 0.0, 0.0); 3];
+        mesh.points = vec![2, 65536];
+        assert!(mesh.into_obj(&LoadOptions::default()).is_err());
+    }
+
+    fn written(obj: &Obj) -> String {
+        let mut out = Vec::new();
+        write(obj, &mut out, None, &WriteOptions::default()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_resets_material() {
+        let mut obj = model();
+        let text = written(&obj);
+        let usemtl = text.lines().filter(|l| l.starts_with("usemtl")).collect::<Vec<_>>();
+        assert_eq!(usemtl, ["usemtl red", "usemtl default", "usemtl blue"]);
+
+        obj.materials[1].name = "default".to_owned();
+        let text = written(&obj);
+        let usemtl = text.lines().filter(|l| l.starts_with("usemtl")).collect::<Vec<_>>();
+        assert_eq!(usemtl, ["usemtl red", "usemtl default_", "usemtl default"]);
+    }
+
+    #[test]
+    fn write_names_every_object_and_group() {
+        let text = written(&model());
+        let groups = text.lines().filter(|l| l.starts_with("o") || l.starts_with("g")).collect::<Vec<_>>();
+        assert_eq!(groups, ["g loose", "o box", "g lid"]);
+    }
+}
@@ -0,0 +1,274 @@
+//! Reader for the Object File Format of Geomview, as used by many
+//! geometry processing datasets.
+//!
+//! The header keyword may carry the `ST`, `C` and `N` prefixes, for
+//! vertices with texture coordinates, colors and normals. Faces of one or
+//! two vertices become points and line segments, colors of faces are
+//! ignored, and a file without faces is read as a point cloud.
+
+use std::cmp;
+use std::str::SplitWhitespace;
+
+use na::{Vec3, Vec4};
+
+use float;
+use obj::{IndexedMesh, Line, LoadOptions, Obj, ObjError, Result};
+
+/// Whether the data starts with an OFF header keyword.
+pub fn is_off(data: &[u8]) -> bool {
+    let keyword = data.split(|&b| (b as char).is_whitespace()).next().unwrap_or(&[]);
+    Layout::parse(&String::from_utf8_lossy(keyword)).is_some()
+}
+
+pub fn parse_bytes(data: &[u8], options: &LoadOptions) -> Result<Obj> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines()
+                        .enumerate()
+                        .map(|(i, text)| Line { number: i + 1, text: text.split('#').next().unwrap_or("") })
+                        .filter(|line| !line.text.trim().is_empty());
+
+    // Errors at the end point to the last line, or the first of an empty text.
+    let line_count = cmp::max(text.lines().count(), 1);
+
+    let header = try!(next_line(&mut lines, line_count, "`OFF`"));
+    let mut words = header.text.split_whitespace();
+    let keyword = words.next().unwrap_or(header.end());
+    let layout = try!(Layout::parse(keyword).ok_or_else(|| header.syntax_error(keyword, "`OFF`, optionally prefixed by `ST`, `C` or `N`")));
+
+    // The counts may follow the keyword on the same line.
+    let (counts_line, mut words) = match header.text.split_whitespace().nth(1) {
+        Some("BINARY") => {
+            return Err(ObjError::NotSupported {
+                location: header.location(words.next().unwrap()),
+                feature: "binary OFF files",
+            })
+        }
+        Some(_) => (header, words),
+        None => {
+            let line = try!(next_line(&mut lines, line_count, "the vertex, face and edge counts"));
+            (line, line.text.split_whitespace())
+        }
+    };
+    let vertex_count = try!(parse_count(counts_line, &mut words, "the vertex count"));
+    let face_count = try!(parse_count(counts_line, &mut words, "the face count"));
+
+    let mut vertices = Vec::with_capacity(vertex_count);
+    for _ in 0..vertex_count {
+        vertices.push(try!(next_line(&mut lines, line_count, "a vertex")));
+    }
+    let color_scale = layout.color_scale(&vertices);
+    let mut mesh = IndexedMesh::default();
+    for &line in &vertices {
+        try!(layout.vertex(line, color_scale, &mut mesh));
+    }
+
+    let mut polygon = Vec::new();
+    for _ in 0..face_count {
+        let line = try!(next_line(&mut lines, line_count, "a face"));
+        let mut words = line.text.split_whitespace();
+        let count = try!(parse_count(line, &mut words, "the number of vertices"));
+        polygon.clear();
+        for _ in 0..count {
+            let token = try!(words.next().ok_or_else(|| line.syntax_error(line.end(), "a vertex index")));
+            let index = try!(token.parse::<i64>().map_err(|_| line.syntax_error(token, "a vertex index")));
+            if index < 0 || index as usize >= vertex_count {
+                return Err(ObjError::IndexOutOfRange {
+                    location: line.location(token),
+                    index: index,
+                    count: vertex_count,
+                });
+            }
+            polygon.push(index as u32);
+        }
+        match count {
+            0 => (),
+            1 => mesh.points.push(polygon[0]),
+            2 => mesh.lines.extend_from_slice(&polygon),
+            _ => mesh.add_polygon(&polygon),
+        }
+    }
+
+    if face_count == 0 {
+        mesh.points = (0..vertex_count as u32).collect();
+    }
+    mesh.into_obj(options)
+}
+
+/// The next line which is not empty, `line_count` is that of the whole
+/// text for reporting its end.
+fn next_line<'a, I>(lines: &mut I, line_count: usize, expected: &'static str) -> Result<Line<'a>>
+    where I: Iterator<Item = Line<'a>>
+{
+    lines.next().ok_or_else(|| {
+        let line = Line { number: line_count, text: "" };
+        line.syntax_error(line.end(), expected)
+    })
+}
+
+fn parse_count<'a>(line: Line<'a>, words: &mut SplitWhitespace<'a>, expected: &'static str) -> Result<usize> {
+    let token = try!(words.next().ok_or_else(|| line.syntax_error(line.end(), expected)));
+    token.parse().map_err(|_| line.syntax_error(token, expected))
+}
+
+/// Which attributes the vertices have, in the order they are written.
+struct Layout {
+    normals: bool,
+    colors: bool,
+    tex_coords: bool,
+}
+
+impl Layout {
+    /// Parses `[ST][C][N]OFF`.
+    fn parse(keyword: &str) -> Option<Layout> {
+        let mut rest = keyword;
+        let layout = Layout {
+            tex_coords: strip_prefix(&mut rest, "ST"),
+            colors: strip_prefix(&mut rest, "C"),
+            normals: strip_prefix(&mut rest, "N"),
+        };
+        if rest == "OFF" { Some(layout) } else { None }
+    }
+
+    /// Colors are either integers up to 255 or fractions, decided for the
+    /// whole file: 255 if all colors are integers and some are above 1,
+    /// otherwise 1.
+    fn color_scale(&self, vertices: &[Line]) -> f32 {
+        if !self.colors {
+            return 1.0;
+        }
+        let start = 3 + if self.normals { 3 } else { 0 };
+        let end = if self.tex_coords { 2 } else { 0 };
+        let mut above_one = false;
+        for line in vertices {
+            let tokens = line.text.split_whitespace().collect::<Vec<_>>();
+            // Too few values are reported when parsing the vertex.
+            if tokens.len() < start + end {
+                continue;
+            }
+            for token in &tokens[start..tokens.len() - end] {
+                match token.parse::<u8>() {
+                    Ok(value) => above_one |= value > 1,
+                    Err(_) => return 1.0,
+                }
+            }
+        }
+        if above_one { 255.0 } else { 1.0 }
+    }
+
+    /// Parses `x y z [nx ny nz] [r g b [a]] [s t]`, dividing colors by
+    /// `color_scale`.
+    fn vertex(&self, line: Line, color_scale: f32, mesh: &mut IndexedMesh) -> Result<()> {
+        let tokens = line.text.split_whitespace().collect::<Vec<_>>();
+        let fixed = 3 + if self.normals { 3 } else { 0 } + if self.tex_coords { 2 } else { 0 };
+        let (min, max) = if self.colors { (fixed + 3, fixed + 4) } else { (fixed, fixed) };
+        if tokens.len() < min {
+            return Err(line.syntax_error(line.end(), "the coordinates and attributes of a vertex"));
+        }
+        if tokens.len() > max {
+            return Err(line.syntax_error(tokens[max], "the end of the vertex"));
+        }
+        let color_count = tokens.len() - fixed;
+        let mut values = Vec::with_capacity(tokens.len());
+        for token in &tokens {
+            values.push(try!(float::parse(token).ok_or_else(|| line.syntax_error(token, "a number"))));
+        }
+
+        mesh.positions.push(Vec3::new(values[0], values[1], values[2]));
+        let mut i = 3;
+        if self.normals {
+            mesh.normals.push(Vec3::new(values[i], values[i + 1], values[i + 2]));
+            i += 3;
+        }
+        if self.colors {
+            let alpha = if color_count == 4 { values[i + 3] / color_scale } else { 1.0 };
+            mesh.colors.push(Vec4::new(values[i] / color_scale, values[i + 1] / color_scale, values[i + 2] / color_scale, alpha));
+            i += color_count;
+        }
+        if self.tex_coords {
+            mesh.tex_coords.push(Vec3::new(values[i], values[i + 1], 0.0));
+        }
+        Ok(())
+    }
+}
+
+fn strip_prefix(text: &mut &str, prefix: &str) -> bool {
+    if text.starts_with(prefix) {
+        *text = &text[prefix.len()..];
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use na::{Vec3, Vec4};
+
+    use obj::{LoadOptions, Obj, ObjError};
+    use super::{is_off, parse_bytes};
+
+    fn parse(text: &str) -> Obj {
+        parse_bytes(text.as_bytes(), &LoadOptions::default()).unwrap()
+    }
+
+    fn indices(obj: &Obj) -> Vec<u32> {
+        (0..obj.indices.len()).map(|i| obj.indices.get(i)).collect()
+    }
+
+    #[test]
+    fn headers() {
+        for keyword in &["OFF", "COFF", "NOFF", "STOFF", "STCNOFF"] {
+            assert!(is_off(format!("{}\n3 1 0\n", keyword).as_bytes()), "{}", keyword);
+        }
+        for keyword in &["", "OF", "NCOFF", "OFFX", "ply"] {
+            assert!(!is_off(format!("{}\n3 1 0\n", keyword).as_bytes()), "{}", keyword);
+        }
+
+        let obj = parse("NOFF\n3 1 0\n0 0 0 0 0 1\n1 0 0 0 0 1\n0 1 0 0 0 1\n3 0 1 2\n");
+        assert!(obj.normals.iter().all(|n| n.normal == Vec3::new(0.0, 0.0, 1.0)));
+        let obj = parse("STOFF\n3 1 0\n0 0 0 0 0\n1 0 0 1 0\n0 1 0 0 1\n3 0 1 2\n");
+        assert_eq!(obj.tex_coords[1].tex_coord, Vec3::new(1.0, 0.0, 0.0));
+        // The counts may follow the keyword, and comments anything.
+        let obj = parse("# a triangle\nOFF 3 1 0 # counts\n\n0 0 0\n1 0 0\n0 1 0 # last\n3 0 1 2\n");
+        assert_eq!(indices(&obj), [0, 1, 2]);
+    }
+
+    #[test]
+    fn colors() {
+        let header = "COFF\n3 1 0\n";
+        let face = "3 0 1 2\n";
+        // Integers above 1 are out of 255, the alpha is optional.
+        let obj = parse(&format!("{}0 0 0 255 0 0\n1 0 0 0 51 0 255\n0 1 0 0 0 255 0\n{}", header, face));
+        let colors = obj.colors.iter().map(|c| c.color).collect::<Vec<_>>();
+        assert_eq!(colors, [Vec4::new(1.0, 0.0, 0.0, 1.0), Vec4::new(0.0, 0.2, 0.0, 1.0), Vec4::new(0.0, 0.0, 1.0, 0.0)]);
+        // Otherwise, they are fractions.
+        for vertices in &["0 0 0 1 0 0\n1 0 0 0 1 0 1\n0 1 0 0 0 1 0\n",
+                          "0 0 0 1 0 0\n1 0 0 0 1 0 1\n0 1 0 0 0 255 0.5\n"] {
+            let obj = parse(&format!("{}{}{}", header, vertices, face));
+            assert!(obj.colors[0].color == Vec4::new(1.0, 0.0, 0.0, 1.0), "{:?}", vertices);
+        }
+    }
+
+    #[test]
+    fn elements() {
+        let vertices = "0 0 0\n1 0 0\n1 1 0\n0 1 0\n";
+        let obj = parse(&format!("OFF\n4 4 0\n{}4 0 1 2 3\n2 0 2\n1 3\n0\n", vertices));
+        assert_eq!(obj.indices.len(), 6);
+        assert_eq!((obj.edges.len(), obj.lines.len(), obj.points.len()), (8, 2, 1));
+
+        // Without faces, the vertices are points.
+        let obj = parse(&format!("OFF\n4 0 0\n{}", vertices));
+        assert_eq!((obj.indices.len(), obj.points.len()), (0, 4));
+
+        match parse_bytes(format!("OFF\n4 1 0\n{}3 0 1 4\n", vertices).as_bytes(), &LoadOptions::default()) {
+            Err(ObjError::IndexOutOfRange { ref location, index: 4, count: 4 }) => assert_eq!(location.line, 7),
+            other => panic!("expected an index out of range, got {:?}", other),
+        }
+        match parse_bytes(b"OFF BINARY\n", &LoadOptions::default()) {
+            Err(ObjError::NotSupported { .. }) => (),
+            other => panic!("expected binary OFF to be unsupported, got {:?}", other),
+        }
+        let err = parse_bytes(format!("OFF\n4 2 0\n{}3 0 1 2\n", vertices).as_bytes(), &LoadOptions::default()).unwrap_err();
+        assert_eq!(err.location().unwrap().line, 7);
+    }
+}
@@ -0,0 +1,607 @@
+//! Reader for the PLY format of the Stanford 3D Scanning Repository, both
+//! ASCII and binary.
+//!
+//! Vertices may have any properties, of which positions, normals, colors
+//! and texture coordinates are used. Faces are read from the
+//! `vertex_indices` list of the `face` element. Other elements, such as
+//! the `range_grid` of raw scans, are skipped.
+
+use std::io;
+use std::str::SplitWhitespace;
+
+use na::{Vec3, Vec4};
+
+use float::{f32_from_bits, f64_from_bits};
+use obj::{IndexedMesh, Line, LoadOptions, Obj, ObjError, Result};
+
+/// Whether the data looks like a PLY file.
+pub fn is_ply(data: &[u8]) -> bool {
+    data.starts_with(b"ply\n") || data.starts_with(b"ply\r\n")
+}
+
+pub fn parse_bytes(data: &[u8], options: &LoadOptions) -> Result<Obj> {
+    let (header, body) = split_header(data);
+    let header_text = String::from_utf8_lossy(header);
+    let header = try!(parse_header(&header_text));
+
+    let mut mesh = IndexedMesh::default();
+    match header.format {
+        Format::Ascii => {
+            let text = String::from_utf8_lossy(body);
+            let mut values = AsciiValues::new(&text, header.line_count);
+            try!(read_body(&header, &mut values, &mut mesh));
+        }
+        Format::Binary(big_endian) => {
+            let mut values = BinaryValues {
+                data: body,
+                offset: 0,
+                big_endian: big_endian,
+            };
+            try!(read_body(&header, &mut values, &mut mesh));
+        }
+    }
+    mesh.into_obj(options)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Type> {
+        match name {
+            "char" | "int8" => Some(Type::I8),
+            "uchar" | "uint8" => Some(Type::U8),
+            "short" | "int16" => Some(Type::I16),
+            "ushort" | "uint16" => Some(Type::U16),
+            "int" | "int32" => Some(Type::I32),
+            "uint" | "uint32" => Some(Type::U32),
+            "float" | "float32" => Some(Type::F32),
+            "double" | "float64" => Some(Type::F64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Type::I8 | Type::U8 => 1,
+            Type::I16 | Type::U16 => 2,
+            Type::I32 | Type::U32 | Type::F32 => 4,
+            Type::F64 => 8,
+        }
+    }
+
+    /// The value meaning full intensity, for colors of this type.
+    fn color_scale(self) -> f64 {
+        match self {
+            Type::I8 => 127.0,
+            Type::U8 => 255.0,
+            Type::I16 => 32767.0,
+            Type::U16 => 65535.0,
+            Type::I32 => 2147483647.0,
+            Type::U32 => 4294967295.0,
+            Type::F32 | Type::F64 => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Property {
+    Scalar(Type, String),
+    /// Types of the length and of the items.
+    List(Type, Type, String),
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match *self {
+            Property::Scalar(_, ref name) | Property::List(_, _, ref name) => name,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Ascii,
+    /// Whether the numbers are big-endian.
+    Binary(bool),
+}
+
+#[derive(Debug)]
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    line_count: usize,
+}
+
+/// Splits the data after the `end_header` line.
+fn split_header(data: &[u8]) -> (&[u8], &[u8]) {
+    let mut start = 0;
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' {
+            if data[start..i].starts_with(b"end_header") {
+                return data.split_at(i + 1);
+            }
+            start = i + 1;
+        }
+    }
+    (data, &[])
+}
+
+fn parse_header(text: &str) -> Result<Header> {
+    let mut header = Header {
+        format: Format::Ascii,
+        elements: Vec::new(),
+        line_count: 0,
+    };
+    let mut has_format = false;
+
+    for (i, text) in text.lines().enumerate() {
+        let line = Line { number: i + 1, text: text };
+        header.line_count = i + 1;
+        let words = text.split_whitespace().collect::<Vec<_>>();
+        if i == 0 {
+            if words != ["ply"] {
+                return Err(line.syntax_error(words.get(0).cloned().unwrap_or(line.end()), "`ply`"));
+            }
+            continue;
+        }
+        if words.is_empty() {
+            continue;
+        }
+        match words[0] {
+            "format" => {
+                let token = words.get(1).cloned().unwrap_or(line.end());
+                header.format = match token {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::Binary(false),
+                    "binary_big_endian" => Format::Binary(true),
+                    _ => return Err(line.syntax_error(token, "`ascii`, `binary_little_endian` or `binary_big_endian`")),
+                };
+                has_format = true;
+            }
+            "element" => {
+                if words.len() != 3 {
+                    let token = words.get(3).cloned().unwrap_or(line.end());
+                    return Err(line.syntax_error(token, "an element name and count"));
+                }
+                header.elements.push(Element {
+                    name: words[1].to_owned(),
+                    count: try!(words[2].parse().map_err(|_| line.syntax_error(words[2], "an element count"))),
+                    properties: Vec::new(),
+                });
+            }
+            "property" => {
+                let property = try!(parse_property(line, &words[1..]));
+                let element = try!(header.elements
+                                         .last_mut()
+                                         .ok_or_else(|| line.syntax_error(words[0], "`element`")));
+                element.properties.push(property);
+            }
+            "end_header" => {
+                if !has_format {
+                    return Err(line.syntax_error(words[0], "`format`"));
+                }
+                return Ok(header);
+            }
+            "comment" | "obj_info" => (),
+            _ => return Err(line.syntax_error(words[0], "`format`, `element`, `property` or `end_header`")),
+        }
+    }
+
+    let line = Line { number: header.line_count, text: "" };
+    Err(line.syntax_error(line.end(), "`end_header`"))
+}
+
+/// Parses `type name` or `list count_type item_type name`.
+fn parse_property(line: Line, args: &[&str]) -> Result<Property> {
+    let parse_type = |token: &str| Type::parse(token).ok_or_else(|| line.syntax_error(token, "a property type"));
+    match args.len() {
+        2 if args[0] != "list" => Ok(Property::Scalar(try!(parse_type(args[0])), args[1].to_owned())),
+        4 if args[0] == "list" => {
+            let count = try!(parse_type(args[1]));
+            if count == Type::F32 || count == Type::F64 {
+                return Err(line.syntax_error(args[1], "an integer type"));
+            }
+            Ok(Property::List(count, try!(parse_type(args[2])), args[3].to_owned()))
+        }
+        _ => Err(line.syntax_error(line.end(), "`type name` or `list count_type item_type name`")),
+    }
+}
+
+/// The values of the elements, in the order given by the header.
+trait Values {
+    /// Moves to the next element.
+    fn start(&mut self) -> Result<()>;
+    fn value(&mut self, ty: Type) -> Result<f64>;
+    /// Checks that all values of the element were read.
+    fn end(&mut self) -> Result<()>;
+}
+
+/// ASCII elements are written one per line.
+struct AsciiValues<'a> {
+    lines: ::std::iter::Enumerate<::std::str::Lines<'a>>,
+    /// Number of the line before the first one of `lines`.
+    first_line: usize,
+    line: Line<'a>,
+    words: SplitWhitespace<'a>,
+}
+
+impl<'a> AsciiValues<'a> {
+    fn new(text: &'a str, first_line: usize) -> AsciiValues<'a> {
+        AsciiValues {
+            lines: text.lines().enumerate(),
+            first_line: first_line,
+            line: Line { number: first_line, text: "" },
+            words: "".split_whitespace(),
+        }
+    }
+}
+
+impl<'a> Values for AsciiValues<'a> {
+    fn start(&mut self) -> Result<()> {
+        for (i, text) in &mut self.lines {
+            if !text.trim().is_empty() {
+                self.line = Line { number: self.first_line + i + 1, text: text };
+                self.words = text.split_whitespace();
+                return Ok(());
+            }
+        }
+        Err(self.line.syntax_error(self.line.end(), "more elements"))
+    }
+
+    fn value(&mut self, ty: Type) -> Result<f64> {
+        let line = self.line;
+        let token = try!(self.words.next().ok_or_else(|| line.syntax_error(line.end(), "a value")));
+        let value = try!(token.parse::<f64>().map_err(|_| line.syntax_error(token, "a number")));
+        if ty != Type::F32 && ty != Type::F64 && value.fract() != 0.0 {
+            return Err(line.syntax_error(token, "an integer"));
+        }
+        Ok(value)
+    }
+
+    fn end(&mut self) -> Result<()> {
+        match self.words.next() {
+            Some(token) => Err(self.line.syntax_error(token, "the end of the element")),
+            None => Ok(()),
+        }
+    }
+}
+
+struct BinaryValues<'a> {
+    data: &'a [u8],
+    offset: usize,
+    big_endian: bool,
+}
+
+impl<'a> Values for BinaryValues<'a> {
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn value(&mut self, ty: Type) -> Result<f64> {
+        let size = ty.size();
+        if self.data.len() - self.offset < size {
+            return Err(ObjError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "PLY data ends before the last element")));
+        }
+        let mut bytes = [0; 8];
+        bytes[..size].clone_from_slice(&self.data[self.offset..self.offset + size]);
+        self.offset += size;
+        if self.big_endian {
+            bytes[..size].reverse();
+        }
+        let bits = (0..size).fold(0u64, |bits, i| bits | (bytes[i] as u64) << (8 * i));
+        Ok(match ty {
+            Type::I8 => bits as u8 as i8 as f64,
+            Type::U8 => bits as u8 as f64,
+            Type::I16 => bits as u16 as i16 as f64,
+            Type::U16 => bits as u16 as f64,
+            Type::I32 => bits as u32 as i32 as f64,
+            Type::U32 => bits as u32 as f64,
+            Type::F32 => f32_from_bits(bits as u32) as f64,
+            Type::F64 => f64_from_bits(bits),
+        })
+    }
+
+    fn end(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Which properties of the vertex element hold the attributes we use.
+struct VertexLayout {
+    position: [usize; 3],
+    normal: Option<[usize; 3]>,
+    /// Alpha is optional.
+    color: Option<([usize; 3], Option<usize>)>,
+    tex_coord: Option<[usize; 2]>,
+}
+
+impl VertexLayout {
+    fn new(element: &Element) -> Option<VertexLayout> {
+        let find = |names: &[&str]| {
+            element.properties.iter().position(|p| {
+                match *p {
+                    Property::Scalar(_, ref name) => names.contains(&&name[..]),
+                    Property::List(..) => false,
+                }
+            })
+        };
+        let find3 = |a: &[&str], b: &[&str], c: &[&str]| {
+            match (find(a), find(b), find(c)) {
+                (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                _ => None,
+            }
+        };
+        let tex_coord = match (find(&["s", "u", "texture_u", "texture_s"]), find(&["t", "v", "texture_v", "texture_t"])) {
+            (Some(u), Some(v)) => Some([u, v]),
+            _ => None,
+        };
+        let position = match find3(&["x"], &["y"], &["z"]) {
+            Some(position) => position,
+            None => return None,
+        };
+        Some(VertexLayout {
+            position: position,
+            normal: find3(&["nx"], &["ny"], &["nz"]),
+            color: find3(&["red", "diffuse_red", "r"], &["green", "diffuse_green", "g"], &["blue", "diffuse_blue", "b"])
+                       .map(|rgb| (rgb, find(&["alpha", "a"]))),
+            tex_coord: tex_coord,
+        })
+    }
+}
+
+fn read_body<V: Values>(header: &Header, values: &mut V, mesh: &mut IndexedMesh) -> Result<()> {
+    let vertex_count = header.elements.iter().find(|e| e.name == "vertex").map_or(0, |e| e.count);
+    // Faces are kept until all vertices are known, as they may come first.
+    let mut polygons = Vec::new();
+    let mut sizes = Vec::new();
+    let mut row = Vec::new();
+    let mut list = Vec::new();
+
+    for element in &header.elements {
+        let layout = if element.name == "vertex" {
+            Some(try!(VertexLayout::new(element).ok_or_else(|| missing_position(header))))
+        } else {
+            None
+        };
+        let indices = if element.name == "face" {
+            element.properties.iter().position(|p| p.name() == "vertex_indices" || p.name() == "vertex_index")
+        } else {
+            None
+        };
+
+        for number in 0..element.count {
+            try!(values.start());
+            row.clear();
+            for (i, property) in element.properties.iter().enumerate() {
+                match *property {
+                    Property::Scalar(ty, _) => row.push(try!(values.value(ty))),
+                    Property::List(count_type, item_type, _) => {
+                        row.push(0.0);
+                        let count = try!(values.value(count_type));
+                        if count < 0.0 {
+                            return Err(ObjError::Io(io::Error::new(io::ErrorKind::InvalidData, "negative PLY list length")));
+                        }
+                        list.clear();
+                        for _ in 0..count as usize {
+                            list.push(try!(values.value(item_type)));
+                        }
+                        if Some(i) == indices {
+                            for &index in &list {
+                                if index < 0.0 || index >= vertex_count as f64 {
+                                    return Err(ObjError::InvalidIndex {
+                                        primitive: "face",
+                                        number: number,
+                                        index: if index < 0.0 { u32::max_value() } else { index as u32 },
+                                        count: vertex_count,
+                                    });
+                                }
+                                polygons.push(index as u32);
+                            }
+                            sizes.push(list.len());
+                        }
+                    }
+                }
+            }
+            try!(values.end());
+
+            if let Some(ref layout) = layout {
+                add_vertex(layout, element, &row, mesh);
+            }
+        }
+    }
+
+    let mut start = 0;
+    for size in sizes {
+        if size >= 3 {
+            mesh.add_polygon(&polygons[start..start + size]);
+        }
+        start += size;
+    }
+    if mesh.indices.is_empty() {
+        mesh.points = (0..mesh.positions.len() as u32).collect();
+    }
+    Ok(())
+}
+
+fn add_vertex(layout: &VertexLayout, element: &Element, row: &[f64], mesh: &mut IndexedMesh) {
+    let vec3 = |i: [usize; 3]| Vec3::new(row[i[0]] as f32, row[i[1]] as f32, row[i[2]] as f32);
+    mesh.positions.push(vec3(layout.position));
+    if let Some(normal) = layout.normal {
+        mesh.normals.push(vec3(normal));
+    }
+    if let Some((rgb, alpha)) = layout.color {
+        let component = |i: usize| {
+            match element.properties[i] {
+                Property::Scalar(ty, _) => (row[i] / ty.color_scale()) as f32,
+                Property::List(..) => 0.0,
+            }
+        };
+        mesh.colors.push(Vec4::new(component(rgb[0]),
+                                   component(rgb[1]),
+                                   component(rgb[2]),
+                                   alpha.map_or(1.0, component)));
+    }
+    if let Some(uv) = layout.tex_coord {
+        mesh.tex_coords.push(Vec3::new(row[uv[0]] as f32, row[uv[1]] as f32, 0.0));
+    }
+}
+
+fn missing_position(header: &Header) -> ObjError {
+    let line = Line { number: header.line_count, text: "" };
+    line.syntax_error(line.end(), "`x`, `y` and `z` vertex properties")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use na::{Vec3, Vec4};
+
+    use obj::{LoadOptions, Obj, ObjError};
+    use super::parse_bytes;
+
+    const POSITIONS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.5, 2.0, 0.0]];
+    const FACES: [&'static [i32]; 2] = [&[0, 1, 2], &[1, 2, 3, 0]];
+
+    fn parse(data: &[u8]) -> Obj {
+        parse_bytes(data, &LoadOptions::default()).unwrap()
+    }
+
+    fn positions(obj: &Obj) -> Vec<Vec3<f32>> {
+        obj.vertices.iter().map(|v| v.position).collect()
+    }
+
+    fn indices(obj: &Obj) -> Vec<u32> {
+        (0..obj.indices.len()).map(|i| obj.indices.get(i)).collect()
+    }
+
+    fn header(format: &str, faces: usize) -> String {
+        format!("ply\nformat {} 1.0\ncomment made by hand\nelement vertex 4\nproperty float x\n\
+                 property float y\nproperty float z\nelement face {}\nproperty list uchar int vertex_indices\n\
+                 end_header\n",
+                format,
+                faces)
+    }
+
+    fn ascii() -> String {
+        let mut data = header("ascii", FACES.len());
+        for p in &POSITIONS {
+            data.push_str(&format!("{} {} {}\n", p[0], p[1], p[2]));
+        }
+        for face in &FACES {
+            data.push_str(&face.len().to_string());
+            for index in face.iter() {
+                data.push_str(&format!(" {}", index));
+            }
+            data.push('\n');
+        }
+        data
+    }
+
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut data = header(format, FACES.len()).into_bytes();
+        {
+            let mut push = |value: u32, size: usize| {
+                let bytes = (0..size).map(|i| (value >> (8 * i)) as u8).collect::<Vec<_>>();
+                if big_endian {
+                    data.extend(bytes.iter().rev());
+                } else {
+                    data.extend(bytes.iter());
+                }
+            };
+            for p in &POSITIONS {
+                for &x in p {
+                    push(unsafe { mem::transmute::<f32, u32>(x) }, 4);
+                }
+            }
+            for face in &FACES {
+                push(face.len() as u32, 1);
+                for &index in face.iter() {
+                    push(index as u32, 4);
+                }
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn formats() {
+        let expected = POSITIONS.iter().map(|p| Vec3::new(p[0], p[1], p[2])).collect::<Vec<_>>();
+        for data in &[ascii().into_bytes(), binary(false), binary(true)] {
+            let obj = parse(data);
+            assert_eq!(positions(&obj), expected);
+            // The quad is triangulated, its outline is kept.
+            assert_eq!(indices(&obj).len(), 3 + 6);
+            assert_eq!(&indices(&obj)[..3], [0, 1, 2]);
+            assert_eq!(obj.edges.len(), 2 * (3 + 4));
+            assert_eq!(obj.points.len(), 0);
+        }
+    }
+
+    #[test]
+    fn vertex_attributes() {
+        let data = "ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\n\
+                    property float nx\nproperty float ny\nproperty float nz\nproperty uchar red\n\
+                    property uchar green\nproperty uchar blue\nelement face 0\n\
+                    property list uchar int vertex_indices\nend_header\n\
+                    0 0 0 0 0 1 255 0 51\n1 0 0 0 1 0 0 255 0\n";
+        let obj = parse(data.as_bytes());
+        // Without faces, the vertices are points.
+        assert_eq!(indices(&obj).len(), 0);
+        assert_eq!((0..obj.points.len()).map(|i| obj.points.get(i)).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(obj.normals[0].normal, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(obj.colors[0].color, Vec4::new(1.0, 0.0, 0.2, 1.0));
+
+        let data = "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\n\
+                    property float z\nend_header\n1 2 3\n";
+        let obj = parse(data.as_bytes());
+        assert_eq!(positions(&obj), [Vec3::new(1.0, 2.0, 3.0)]);
+        assert_eq!(obj.points.len(), 1);
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        for &(face, index) in &[("3 0 1 4", 4), ("3 0 -1 2", u32::max_value())] {
+            let data = ascii().replace("3 0 1 2", face);
+            match parse_bytes(data.as_bytes(), &LoadOptions::default()) {
+                Err(ObjError::InvalidIndex { primitive: "face", number: 0, index: i, count: 4 }) => assert_eq!(i, index),
+                other => panic!("expected an invalid index for `{}`, got {:?}", face, other),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_body() {
+        let data = binary(false);
+        for len in &[data.len() - 1, data.len() - 17, data.len() - 40] {
+            match parse_bytes(&data[..*len], &LoadOptions::default()) {
+                Err(ObjError::Io(_)) => (),
+                other => panic!("expected an unexpected end of file at {} bytes, got {:?}", len, other),
+            }
+        }
+
+        let data = ascii();
+        let data = &data[..data.len() - "4 1 2 3 0\n".len()];
+        let err = parse_bytes(data.as_bytes(), &LoadOptions::default()).unwrap_err();
+        // The error points past the last face which is there.
+        assert_eq!(err.location().unwrap().line, 15);
+    }
+}
@@ -1,18 +1,61 @@
 #version 330 core
 
 uniform samplerCube skybox;
+uniform sampler2D diffuse_map;
+uniform sampler2D specular_map;
+
+uniform vec3 light;
+uniform bool use_material;
+uniform bool use_vertex_colors;
+uniform bool highlight;
+uniform vec3 ambient;
+uniform vec3 diffuse;
+uniform vec3 specular;
+uniform float shininess;
+uniform float dissolve;
+uniform int illum;
 
 in vec3 v_normal;
 in vec3 v_position;
+in vec3 v_tex_coord;
+in vec4 v_color;
 in vec3 camera_pos;
 
 out vec4 color;
 
 
+vec4 shade(vec3 view, vec3 normal, vec4 sky)
+{
+    vec4 kd = v_color * vec4(diffuse, dissolve) * texture(diffuse_map, v_tex_coord.xy);
+    vec3 ks = specular * texture(specular_map, v_tex_coord.xy).rgb;
+    vec3 to_light = normalize(light - v_position);
+
+    vec3 result = kd.rgb * (0.2 * ambient + max(dot(normal, to_light), 0.0));
+    if (illum >= 2) {
+        vec3 half_way = normalize(to_light - view);
+        result += ks * pow(max(dot(normal, half_way), 0.0), max(shininess, 1.0));
+    }
+    if (illum >= 3) {
+        result = mix(result, sky.rgb, ks);
+    }
+
+    return vec4(result, kd.a);
+}
+
 void main()
 {
     vec3 view = normalize(v_position - camera_pos);
-    vec3 refl = reflect(view, normalize(v_normal));
+    vec3 normal = normalize(v_normal);
+    vec3 refl = reflect(view, normal);
+    vec4 sky = texture(skybox, -refl);
+
+    if (use_material || use_vertex_colors) {
+        color = shade(view, normal, sky);
+    } else {
+        color = sky;
+    }
 
-    color = texture(skybox, -refl);
+    if (highlight) {
+        color.rgb = mix(color.rgb, vec3(1.0, 0.5, 0.0), 0.5);
+    }
 }
@@ -4,9 +4,13 @@ uniform vec3 camera_position;
 
 in vec3 position;
 in vec3 normal;
+in vec3 tex_coord;
+in vec4 color;
 
 out vec3 v_normal;
 out vec3 v_position;
+out vec3 v_tex_coord;
+out vec4 v_color;
 out vec3 camera_pos;
 
 uniform mat4 proj;
@@ -19,6 +23,8 @@ void main()
 
     v_normal = normal;
     v_position = position;
+    v_tex_coord = tex_coord;
+    v_color = color;
     vec4 t = view * vec4(camera_position, 1);
     camera_pos = t.xyz / t.w;
 }
@@ -0,0 +1,12 @@
+#version 330 core
+
+in vec3 position;
+
+uniform mat4 proj;
+uniform mat4 view;
+
+void main()
+{
+    mat4 vp = proj * view;
+    gl_Position = vp * vec4(position, 1);
+}
@@ -0,0 +1,22 @@
+#version 330 core
+
+in vec4 v_color;
+
+out vec4 color;
+
+// Direction to the light in screen space.
+const vec3 light = normalize(vec3(-0.5, 0.5, 1.0));
+
+void main()
+{
+    // Every point is drawn as a small sphere facing the viewer.
+    vec2 xy = gl_PointCoord * 2.0 - 1.0;
+    float r2 = dot(xy, xy);
+    if (r2 > 1.0) {
+        discard;
+    }
+    vec3 normal = vec3(xy.x, -xy.y, sqrt(1.0 - r2));
+    float diffuse = max(dot(normal, light), 0.0);
+
+    color = vec4(v_color.rgb * (0.3 + 0.7 * diffuse), v_color.a);
+}
@@ -0,0 +1,17 @@
+#version 330 core
+
+in vec3 position;
+in vec4 color;
+
+out vec4 v_color;
+
+uniform mat4 proj;
+uniform mat4 view;
+
+void main()
+{
+    mat4 vp = proj * view;
+    gl_Position = vp * vec4(position, 1);
+
+    v_color = color;
+}
@@ -10,6 +10,8 @@ uniform mat4 view;
 void main()
 {
     mat4 vp = proj * view;
-    gl_Position = vp * vec4(position, 1);
+    // Only the rotation of the view applies, and the depth is that of the
+    // far plane, so the sky stays around the camera wherever it is.
+    gl_Position = (vp * vec4(position, 0)).xyww;
     sky_coords = -position;
 }
@@ -0,0 +1,780 @@
+//! Simplification of triangle meshes by collapsing edges, cheapest first
+//! by the quadric error metric of Garland and Heckbert.
+//!
+//! Vertices are welded by position, texture coordinates and color first, so
+//! that split normals do not cut the mesh apart, and the normals of the
+//! result are generated anew. Edges on the border of the mesh, or between
+//! faces of different materials or groups, are held in place by penalty
+//! planes and only collapse along the border. Vertices where texture
+//! coordinates or colors are discontinuous, vertices of non-manifold edges
+//! and those of lines and points are never moved.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::f32;
+
+use na::Vec3;
+
+use float::f32_to_bits;
+use fnv::FnvBuildHasher;
+use normals::NormalMode;
+use obj::{Group, IndexedMesh, LoadOptions, MaterialRange, Obj, Result};
+
+/// Weight of the planes holding the borders, relative to those of faces.
+const BORDER_WEIGHT: f64 = 100.0;
+
+/// Collapses turning a face further than this, as the cosine of the angle
+/// between its old and new normal, are rejected.
+const MIN_TURN_COS: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct SimplifyOptions {
+    /// Stop once the model has no more triangles than this.
+    pub target_triangles: usize,
+    /// Stop before collapses which move the surface further than this,
+    /// roughly, in the units of the model.
+    pub max_error: f32,
+    /// How to compute the normals of the result.
+    pub normals: NormalMode,
+}
+
+impl Default for SimplifyOptions {
+    fn default() -> SimplifyOptions {
+        SimplifyOptions {
+            target_triangles: 0,
+            max_error: f32::INFINITY,
+            normals: NormalMode::default(),
+        }
+    }
+}
+
+/// Simplifies the triangles of the model. Materials, groups, lines and
+/// points are kept, texture coordinates and colors follow the vertices
+/// which stay.
+pub fn simplify(obj: &Obj, options: &SimplifyOptions) -> Result<Obj> {
+    let mut mesh = Mesh::new(obj);
+    mesh.collapse(options);
+    mesh.into_obj(obj, options)
+}
+
+type Point = [f64; 3];
+
+fn sub(a: Point, b: Point) -> Point {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: Point, b: Point) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Point, b: Point) -> Point {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(a: Point) -> Option<Point> {
+    let norm = dot(a, a).sqrt();
+    if norm > 0.0 { Some([a[0] / norm, a[1] / norm, a[2] / norm]) } else { None }
+}
+
+/// Sum of the squared distances to a set of planes, as the symmetric
+/// matrix `xx xy xz xw yy yz yw zz zw ww`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// The plane through `p` with the unit normal `n`.
+    fn plane(n: Point, p: Point, weight: f64) -> Quadric {
+        let (a, b, c, d) = (n[0], n[1], n[2], -dot(n, p));
+        let q = [a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d];
+        Quadric([q[0] * weight, q[1] * weight, q[2] * weight, q[3] * weight, q[4] * weight,
+                 q[5] * weight, q[6] * weight, q[7] * weight, q[8] * weight, q[9] * weight])
+    }
+
+    fn add(&self, other: &Quadric) -> Quadric {
+        let mut sum = self.0;
+        for (s, o) in sum.iter_mut().zip(other.0.iter()) {
+            *s += *o;
+        }
+        Quadric(sum)
+    }
+
+    fn error(&self, p: Point) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p[0], p[1], p[2]);
+        let error = q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x +
+                    q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y + q[7] * z * z + 2.0 * q[8] * z + q[9];
+        // Rounding can make it slightly negative.
+        error.max(0.0)
+    }
+
+    /// The point of the least error, unless it is not unique.
+    fn optimum(&self) -> Option<Point> {
+        let q = &self.0;
+        let rows = [[q[0], q[1], q[2]], [q[1], q[4], q[5]], [q[2], q[5], q[7]]];
+        let rhs = [-q[3], -q[6], -q[8]];
+        let det = dot(rows[0], cross(rows[1], rows[2]));
+        let scale = rows.iter().map(|r| dot(*r, *r)).fold(0.0, f64::max);
+        if det.abs() <= 1e-12 * scale * scale.sqrt() {
+            return None;
+        }
+        // Cramer's rule, the columns of the inverse are the cross products of the rows.
+        let (c0, c1, c2) = (cross(rows[1], rows[2]), cross(rows[2], rows[0]), cross(rows[0], rows[1]));
+        Some([(c0[0] * rhs[0] + c1[0] * rhs[1] + c2[0] * rhs[2]) / det,
+              (c0[1] * rhs[0] + c1[1] * rhs[1] + c2[1] * rhs[2]) / det,
+              (c0[2] * rhs[0] + c1[2] * rhs[1] + c2[2] * rhs[2]) / det])
+    }
+}
+
+/// Collapse of the edge from `from` to `to`. The point `to` moves to is
+/// found again when the collapse is done, which keeps the list small.
+#[derive(Debug, Clone, Copy)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+}
+
+struct Mesh {
+    positions: Vec<Point>,
+    quadrics: Vec<Quadric>,
+    /// For each welded vertex, the vertex of the `Obj` it was made from.
+    sources: Vec<u32>,
+    /// For each vertex of the `Obj`, the welded vertex.
+    welded: Vec<u32>,
+    locked: Vec<bool>,
+    border: Vec<bool>,
+    faces: Vec<[u32; 3]>,
+    /// Material and group of each face.
+    parts: Vec<u32>,
+    live: Vec<bool>,
+    live_count: usize,
+    /// Faces around each vertex, some of which may be dead.
+    around: Vec<Vec<u32>>,
+}
+
+impl Mesh {
+    fn new(obj: &Obj) -> Mesh {
+        let mut mesh = Mesh {
+            positions: Vec::new(),
+            quadrics: Vec::new(),
+            sources: Vec::new(),
+            welded: Vec::with_capacity(obj.vertices.len()),
+            locked: Vec::new(),
+            border: Vec::new(),
+            faces: Vec::new(),
+            parts: Vec::new(),
+            live: Vec::new(),
+            live_count: 0,
+            around: Vec::new(),
+        };
+        mesh.weld(obj);
+        mesh.add_faces(obj);
+        for primitives in &[&obj.lines, &obj.points] {
+            for i in 0..primitives.len() {
+                let v = mesh.welded[primitives.get(i) as usize] as usize;
+                mesh.locked[v] = true;
+            }
+        }
+        mesh.add_quadrics();
+        mesh
+    }
+
+    fn weld(&mut self, obj: &Obj) {
+        // Adding zero turns -0.0 into 0.0, so that the two are welded.
+        let bits = |x: f32| f32_to_bits(x + 0.0);
+        let mut vertices = HashMap::with_hasher(FnvBuildHasher::default());
+        let mut per_position = HashMap::<[u32; 3], u32, FnvBuildHasher>::default();
+        for v in 0..obj.vertices.len() {
+            let p = obj.vertices[v].position;
+            let t = obj.tex_coords[v].tex_coord;
+            let c = obj.colors.get(v).map_or([0; 4], |c| {
+                [bits(c.color.x), bits(c.color.y), bits(c.color.z), bits(c.color.w)]
+            });
+            let key = [bits(p.x), bits(p.y), bits(p.z), bits(t.x), bits(t.y), bits(t.z), c[0], c[1], c[2], c[3]];
+            let sources = &mut self.sources;
+            let id = *vertices.entry(key).or_insert_with(|| {
+                *per_position.entry([key[0], key[1], key[2]]).or_insert(0) += 1;
+                sources.push(v as u32);
+                (sources.len() - 1) as u32
+            });
+            self.welded.push(id);
+        }
+
+        for &v in &self.sources {
+            let p = obj.vertices[v as usize].position;
+            self.positions.push([p.x as f64, p.y as f64, p.z as f64]);
+            // Moving one of several vertices at a position would tear the seam.
+            self.locked.push(per_position[&[bits(p.x), bits(p.y), bits(p.z)]] > 1);
+        }
+        let count = self.sources.len();
+        self.quadrics = vec![Quadric::default(); count];
+        self.border = vec![false; count];
+        self.around = vec![Vec::new(); count];
+    }
+
+    fn add_faces(&mut self, obj: &Obj) {
+        let face_count = obj.indices.len() / 3;
+        let mut materials = vec![0; face_count];
+        for (i, range) in obj.material_ranges.iter().enumerate() {
+            for f in range.range.start / 3..range.range.end / 3 {
+                materials[f] = i as u32;
+            }
+        }
+        let mut groups = vec![0; face_count];
+        for (i, group) in obj.groups.iter().enumerate() {
+            for f in group.range.start / 3..group.range.end / 3 {
+                groups[f] = i as u32 + 1;
+            }
+        }
+
+        for f in 0..face_count {
+            let face = [self.welded[obj.indices.get(3 * f) as usize],
+                        self.welded[obj.indices.get(3 * f + 1) as usize],
+                        self.welded[obj.indices.get(3 * f + 2) as usize]];
+            let live = face[0] != face[1] && face[1] != face[2] && face[2] != face[0];
+            if live {
+                for &v in &face {
+                    self.around[v as usize].push(f as u32);
+                }
+                self.live_count += 1;
+            }
+            self.faces.push(face);
+            self.parts.push(materials[f] * (obj.groups.len() as u32 + 1) + groups[f]);
+            self.live.push(live);
+        }
+    }
+
+    /// Adds the planes of the faces and of the borders to the quadrics,
+    /// and locks the vertices of edges with more than two faces.
+    fn add_quadrics(&mut self) {
+        for f in 0..self.faces.len() {
+            if !self.live[f] {
+                continue;
+            }
+            let normal = match self.face_normal(f, None) {
+                Some(normal) => normal,
+                None => continue,
+            };
+            for i in 0..3 {
+                let (a, b) = (self.faces[f][i], self.faces[f][(i + 1) % 3]);
+                let (p, q) = (self.positions[a as usize], self.positions[b as usize]);
+                let plane = Quadric::plane(normal, p, 1.0);
+                self.quadrics[a as usize] = self.quadrics[a as usize].add(&plane);
+
+                let (count, is_border) = self.edge_faces(a, b);
+                if count > 2 {
+                    self.locked[a as usize] = true;
+                    self.locked[b as usize] = true;
+                } else if is_border {
+                    // A plane through the edge, perpendicular to the face.
+                    if let Some(n) = normalize(cross(sub(q, p), normal)) {
+                        let plane = Quadric::plane(n, p, BORDER_WEIGHT);
+                        self.quadrics[a as usize] = self.quadrics[a as usize].add(&plane);
+                        self.quadrics[b as usize] = self.quadrics[b as usize].add(&plane);
+                    }
+                    self.border[a as usize] = true;
+                    self.border[b as usize] = true;
+                }
+            }
+        }
+    }
+
+    /// The unit normal of a face, with the vertex `moved.0` at `moved.1`.
+    fn face_normal(&self, f: usize, moved: Option<(u32, Point)>) -> Option<Point> {
+        let p = |v: u32| match moved {
+            Some((m, target)) if m == v => target,
+            _ => self.positions[v as usize],
+        };
+        let face = self.faces[f];
+        normalize(cross(sub(p(face[1]), p(face[0])), sub(p(face[2]), p(face[0]))))
+    }
+
+    /// How many live faces share the edge, and whether it is a border:
+    /// one with a single face, or with faces of different parts.
+    fn edge_faces(&self, a: u32, b: u32) -> (usize, bool) {
+        let mut count = 0;
+        let mut parts = None;
+        let mut mixed = false;
+        for &f in &self.around[a as usize] {
+            if self.live[f as usize] && self.faces[f as usize].contains(&b) {
+                count += 1;
+                let part = self.parts[f as usize];
+                mixed |= parts.map_or(false, |p| p != part);
+                parts = Some(part);
+            }
+        }
+        (count, count == 1 || mixed)
+    }
+
+    /// Vertices sharing a live face with `v`.
+    fn neighbors(&self, v: u32) -> Vec<u32> {
+        let mut neighbors = self.around[v as usize]
+                                .iter()
+                                .filter(|&&f| self.live[f as usize])
+                                .flat_map(|&f| self.faces[f as usize].to_vec())
+                                .filter(|&w| w != v)
+                                .collect::<Vec<_>>();
+        neighbors.sort();
+        neighbors.dedup();
+        neighbors
+    }
+
+    /// The cheapest allowed collapse of the edge, if any.
+    fn candidate(&self, a: u32, b: u32, is_border_edge: bool) -> Option<Collapse> {
+        let quadric = self.quadrics[a as usize].add(&self.quadrics[b as usize]);
+        let optimum = quadric.optimum();
+        let mut best: Option<Collapse> = None;
+        for &(from, to) in &[(a, b), (b, a)] {
+            let f = from as usize;
+            // Borders only collapse along themselves.
+            if self.locked[f] || (self.border[f] && !is_border_edge) {
+                continue;
+            }
+            let (_, cost) = self.place(from, to, &quadric, optimum);
+            if best.map_or(true, |best| cost < best.cost) {
+                best = Some(Collapse {
+                    cost: cost,
+                    from: from,
+                    to: to,
+                });
+            }
+        }
+        best
+    }
+
+    /// Where `to` moves when `from` collapses into it, and the error there.
+    fn target(&self, from: u32, to: u32) -> (Point, f64) {
+        let quadric = self.quadrics[from as usize].add(&self.quadrics[to as usize]);
+        self.place(from, to, &quadric, quadric.optimum())
+    }
+
+    /// The point of the least error among the optimum of the quadric of the
+    /// edge, its middle and `to`, which is the only one for borders.
+    fn place(&self, from: u32, to: u32, quadric: &Quadric, optimum: Option<Point>) -> (Point, f64) {
+        let (f, t) = (from as usize, to as usize);
+        if self.locked[t] || self.border[t] || self.border[f] {
+            return (self.positions[t], quadric.error(self.positions[t]));
+        }
+        let (p, q) = (self.positions[f], self.positions[t]);
+        let middle = [(p[0] + q[0]) / 2.0, (p[1] + q[1]) / 2.0, (p[2] + q[2]) / 2.0];
+        let mut best = (q, quadric.error(q));
+        for point in optimum.into_iter().chain(Some(middle)) {
+            let error = quadric.error(point);
+            if error < best.1 {
+                best = (point, error);
+            }
+        }
+        best
+    }
+
+    /// Whether the collapse keeps the mesh manifold and flips no face.
+    fn is_valid(&self, c: &Collapse, target: Point) -> bool {
+        // The only vertices next to both ends must be those of the faces
+        // on the edge, otherwise the collapse would fold the surface.
+        let opposite = self.around[c.from as usize]
+                           .iter()
+                           .filter(|&&f| self.live[f as usize] && self.faces[f as usize].contains(&c.to))
+                           .count();
+        let to_neighbors = self.neighbors(c.to);
+        let common = self.neighbors(c.from).iter().filter(|v| to_neighbors.binary_search(v).is_ok()).count();
+        if common != opposite {
+            return false;
+        }
+        // Nor may a face of `from` land on one of `to`, like when a
+        // tetrahedron would flatten into two faces back to back.
+        for &f in &self.around[c.from as usize] {
+            let face = self.faces[f as usize];
+            if !self.live[f as usize] || face.contains(&c.to) {
+                continue;
+            }
+            let mut moved = face.iter().map(|&v| if v == c.from { c.to } else { v }).collect::<Vec<_>>();
+            moved.sort();
+            let lands = self.around[c.to as usize].iter().any(|&g| {
+                let mut other = self.faces[g as usize].to_vec();
+                other.sort();
+                self.live[g as usize] && other == moved
+            });
+            if lands {
+                return false;
+            }
+        }
+
+        for &(v, other) in &[(c.from, c.to), (c.to, c.from)] {
+            for &f in &self.around[v as usize] {
+                let f = f as usize;
+                if !self.live[f] || self.faces[f].contains(&other) {
+                    continue;
+                }
+                let before = self.face_normal(f, None);
+                let after = self.face_normal(f, Some((v, target)));
+                match (before, after) {
+                    (Some(before), Some(after)) if dot(before, after) < MIN_TURN_COS => return false,
+                    (Some(_), None) => return false,
+                    _ => (),
+                }
+            }
+        }
+        true
+    }
+
+    fn apply(&mut self, c: &Collapse, target: Point) {
+        let (from, to) = (c.from as usize, c.to as usize);
+        let faces = self.around[from].clone();
+        for f in faces {
+            let fi = f as usize;
+            if !self.live[fi] {
+                continue;
+            }
+            if self.faces[fi].contains(&c.to) {
+                self.live[fi] = false;
+                self.live_count -= 1;
+            } else {
+                for v in &mut self.faces[fi] {
+                    if *v == c.from {
+                        *v = c.to;
+                    }
+                }
+                self.around[to].push(f);
+            }
+        }
+        let live = &self.live;
+        self.around[to].retain(|&f| live[f as usize]);
+        self.around[from].clear();
+        self.positions[to] = target;
+        self.quadrics[to] = self.quadrics[to].add(&self.quadrics[from]);
+        self.border[to] = self.border[to] || self.border[from];
+    }
+
+    /// Collapses edges in passes, each going through the collapses of the
+    /// mesh from the cheapest and doing those whose ends did not change yet
+    /// in the pass, which is nearly as good as always doing the cheapest
+    /// one and much faster.
+    fn collapse(&mut self, options: &SimplifyOptions) {
+        let max_cost = (options.max_error as f64) * (options.max_error as f64);
+        let mut collapses = Vec::new();
+        let mut touched = vec![false; self.positions.len()];
+        while self.live_count > options.target_triangles {
+            collapses.clear();
+            for f in 0..self.faces.len() {
+                if !self.live[f] {
+                    continue;
+                }
+                for i in 0..3 {
+                    let (a, b) = (self.faces[f][i], self.faces[f][(i + 1) % 3]);
+                    // Each edge once, interior edges are seen from both
+                    // faces and those of a single face only from it.
+                    if a > b && !self.border[a as usize] {
+                        continue;
+                    }
+                    let (count, is_border) = self.edge_faces(a, b);
+                    if a < b || count == 1 {
+                        collapses.extend(self.candidate(a, b, is_border));
+                    }
+                }
+            }
+            collapses.sort_by(|a, b| a.cost.partial_cmp(&b.cost).unwrap_or(Ordering::Equal));
+
+            // Interior collapses remove two faces. Going much further than
+            // needed would do expensive collapses before cheaper ones made
+            // possible by the pass.
+            let goal = (self.live_count - options.target_triangles + 1) / 2;
+            let mut done = 0;
+            for (i, c) in collapses.iter().enumerate() {
+                if self.live_count <= options.target_triangles || c.cost > max_cost ||
+                   (i > goal + goal / 2 && done > 0) {
+                    break;
+                }
+                if touched[c.from as usize] || touched[c.to as usize] {
+                    continue;
+                }
+                let target = self.target(c.from, c.to).0;
+                if !self.is_valid(c, target) {
+                    continue;
+                }
+                self.apply(c, target);
+                // The other collapses of `to` cost more or less now, those
+                // of its neighbors are unchanged.
+                touched[c.from as usize] = true;
+                touched[c.to as usize] = true;
+                done += 1;
+            }
+            if done == 0 {
+                break;
+            }
+            for t in &mut touched {
+                *t = false;
+            }
+        }
+    }
+
+    fn into_obj(self, obj: &Obj, options: &SimplifyOptions) -> Result<Obj> {
+        let mut mesh = IndexedMesh::default();
+        let mut new_index = vec![None; self.positions.len()];
+        let (triangles, lines, points) = {
+            let mut index = |v: u32| -> u32 {
+                if let Some(i) = new_index[v as usize] {
+                    return i;
+                }
+                let source = self.sources[v as usize] as usize;
+                let p = self.positions[v as usize];
+                mesh.positions.push(Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32));
+                mesh.tex_coords.push(obj.tex_coords[source].tex_coord);
+                if !obj.colors.is_empty() {
+                    mesh.colors.push(obj.colors[source].color);
+                }
+                let i = (mesh.positions.len() - 1) as u32;
+                new_index[v as usize] = Some(i);
+                i
+            };
+            let triangles = self.faces
+                                .iter()
+                                .zip(&self.live)
+                                .filter(|&(_, &live)| live)
+                                .map(|(face, _)| [index(face[0]), index(face[1]), index(face[2])])
+                                .collect::<Vec<_>>();
+            let lines = (0..obj.lines.len()).map(|i| index(self.welded[obj.lines.get(i) as usize])).collect();
+            let points = (0..obj.points.len()).map(|i| index(self.welded[obj.points.get(i) as usize])).collect();
+            (triangles, lines, points)
+        };
+        for triangle in &triangles {
+            mesh.add_polygon(triangle);
+        }
+        mesh.lines = lines;
+        mesh.points = points;
+
+        // Faces keep their order, so ranges of indices only shrink.
+        let mut live_before = vec![0; self.faces.len() + 1];
+        for f in 0..self.faces.len() {
+            live_before[f + 1] = live_before[f] + if self.live[f] { 3 } else { 0 };
+        }
+        let shrink = |r: &::std::ops::Range<usize>| live_before[r.start / 3]..live_before[r.end / 3];
+        mesh.materials = obj.materials.clone();
+        mesh.material_ranges = obj.material_ranges
+                                  .iter()
+                                  .map(|r| MaterialRange { material: r.material, range: shrink(&r.range) })
+                                  .collect();
+        mesh.groups = obj.groups
+                         .iter()
+                         .map(|g| Group { range: shrink(&g.range), ..g.clone() })
+                         .collect();
+
+        let options = LoadOptions { normals: options.normals, ..Default::default() };
+        mesh.into_obj(&options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use na::Vec3;
+
+    use mtl::Material;
+    use obj::{Group, IndexedMesh, LoadOptions, MaterialRange, Obj};
+    use super::{cross, dot, simplify, sub, Point, SimplifyOptions};
+
+    /// A square of `n` by `n` quads in the xy plane, facing up. With a seam,
+    /// the two halves left and right of `x = n / 2` have their own texture
+    /// coordinates.
+    fn grid(n: u32, seam: bool) -> IndexedMesh {
+        let mut mesh = IndexedMesh::default();
+        let half = if seam { n / 2 } else { n };
+        for &(x0, x1, u) in &[(0, half, 0.0), (half, n, 1.0)] {
+            if x0 == x1 {
+                continue;
+            }
+            let first = mesh.positions.len() as u32;
+            let width = x1 - x0 + 1;
+            for y in 0..n + 1 {
+                for x in x0..x1 + 1 {
+                    mesh.positions.push(Vec3::new(x as f32, y as f32, 0.0));
+                    mesh.tex_coords.push(Vec3::new(u, 0.0, 0.0));
+                }
+            }
+            for y in 0..n {
+                for x in 0..x1 - x0 {
+                    let v = first + y * width + x;
+                    mesh.add_polygon(&[v, v + 1, v + width + 1]);
+                    mesh.add_polygon(&[v, v + width + 1, v + width]);
+                }
+            }
+        }
+        mesh
+    }
+
+    /// A cube of two units around the origin, with `n` by `n` quads on
+    /// each side.
+    fn cube(n: u32) -> IndexedMesh {
+        let mut mesh = IndexedMesh::default();
+        for axis in 0..3 {
+            for &side in &[-1.0, 1.0] {
+                let first = mesh.positions.len() as u32;
+                for i in 0..n + 1 {
+                    for j in 0..n + 1 {
+                        let mut p = [0.0; 3];
+                        p[axis] = side;
+                        p[(axis + 1) % 3] = 2.0 * i as f32 / n as f32 - 1.0;
+                        p[(axis + 2) % 3] = 2.0 * j as f32 / n as f32 - 1.0;
+                        mesh.positions.push(Vec3::new(p[0], p[1], p[2]));
+                    }
+                }
+                for i in 0..n {
+                    for j in 0..n {
+                        let v = first + i * (n + 1) + j;
+                        let (a, b, c, d) = (v, v + n + 1, v + n + 2, v + 1);
+                        if side > 0.0 {
+                            mesh.add_polygon(&[a, b, c]);
+                            mesh.add_polygon(&[a, c, d]);
+                        } else {
+                            mesh.add_polygon(&[a, c, b]);
+                            mesh.add_polygon(&[a, d, c]);
+                        }
+                    }
+                }
+            }
+        }
+        mesh
+    }
+
+    fn load(mesh: IndexedMesh) -> Obj {
+        mesh.into_obj(&LoadOptions::default()).unwrap()
+    }
+
+    fn run(obj: &Obj, target_triangles: usize) -> Obj {
+        let options = SimplifyOptions { target_triangles: target_triangles, ..Default::default() };
+        let simplified = simplify(obj, &options).unwrap();
+        assert!(simplified.indices.len() / 3 <= target_triangles,
+                "{} triangles are left of {}, for a target of {}",
+                simplified.indices.len() / 3,
+                obj.indices.len() / 3,
+                target_triangles);
+        simplified
+    }
+
+    fn position(obj: &Obj, v: u32) -> Point {
+        let p = obj.vertices[v as usize].position;
+        [p.x as f64, p.y as f64, p.z as f64]
+    }
+
+    fn triangles(obj: &Obj) -> Vec<[Point; 3]> {
+        (0..obj.indices.len() / 3)
+            .map(|t| {
+                [position(obj, obj.indices.get(3 * t)),
+                 position(obj, obj.indices.get(3 * t + 1)),
+                 position(obj, obj.indices.get(3 * t + 2))]
+            })
+            .collect()
+    }
+
+    /// Twice the area of the triangle along its normal.
+    fn normal(t: &[Point; 3]) -> Point {
+        cross(sub(t[1], t[0]), sub(t[2], t[0]))
+    }
+
+    fn centroid(t: &[Point; 3]) -> Point {
+        [(t[0][0] + t[1][0] + t[2][0]) / 3.0, (t[0][1] + t[1][1] + t[2][1]) / 3.0, (t[0][2] + t[1][2] + t[2][2]) / 3.0]
+    }
+
+    /// Checks that the triangles of a simplified grid all face up, cover
+    /// the square, and leave its corners in place.
+    fn check_grid(obj: &Obj, n: u32) {
+        let mut area = 0.0;
+        for t in &triangles(obj) {
+            let normal = normal(t);
+            assert!(normal[2] > 1e-6 && normal[0].abs() < 1e-6 && normal[1].abs() < 1e-6,
+                    "{:?} is flipped or degenerate",
+                    t);
+            area += normal[2] / 2.0;
+        }
+        assert!((area - (n * n) as f64).abs() < 1e-3, "the area is {}", area);
+        let n = n as f64;
+        for corner in &[[0.0, 0.0, 0.0], [n, 0.0, 0.0], [0.0, n, 0.0], [n, n, 0.0]] {
+            assert!((0..obj.vertices.len()).any(|v| position(obj, v as u32) == *corner), "{:?} moved", corner);
+        }
+    }
+
+    #[test]
+    fn grid_borders() {
+        let obj = load(grid(10, false));
+        for &target in &[100, 20, 2] {
+            check_grid(&run(&obj, target), 10);
+        }
+    }
+
+    #[test]
+    fn seams() {
+        let obj = load(grid(10, true));
+        let simplified = run(&obj, 40);
+        check_grid(&simplified, 10);
+        // Both vertices of every point of the seam are still there.
+        let on_seam = (0..simplified.vertices.len()).filter(|&v| position(&simplified, v as u32)[0] == 5.0).count();
+        assert_eq!(on_seam, 22);
+    }
+
+    #[test]
+    fn parts() {
+        // Materials and groups split the grid into the bottom and top half.
+        let mut mesh = grid(10, false);
+        let half = mesh.indices.len() / 2;
+        let end = mesh.indices.len();
+        mesh.materials = vec![Material::new("bottom"), Material::new("top")];
+        mesh.material_ranges = vec![MaterialRange { material: Some(0), range: 0..half },
+                                    MaterialRange { material: Some(1), range: half..end }];
+        mesh.groups = vec![Group { object: String::new(), name: "bottom".to_owned(), range: 0..half },
+                           Group { object: String::new(), name: "top".to_owned(), range: half..end }];
+        let obj = load(mesh);
+        let simplified = run(&obj, 40);
+        check_grid(&simplified, 10);
+
+        let triangles = triangles(&simplified);
+        let ranges = simplified.material_ranges.iter().map(|r| r.range.clone()).collect::<Vec<_>>();
+        assert_eq!(ranges, simplified.groups.iter().map(|g| g.range.clone()).collect::<Vec<_>>());
+        assert_eq!(ranges.len(), 2);
+        assert_eq!((ranges[0].start, ranges[0].end), (0, ranges[1].start));
+        assert_eq!(ranges[1].end, simplified.indices.len());
+        // The border between the halves stays where it is.
+        for t in &triangles[..ranges[0].end / 3] {
+            assert!(centroid(t)[1] < 5.0, "{:?} is not in the bottom half", t);
+        }
+        for t in &triangles[ranges[1].start / 3..] {
+            assert!(centroid(t)[1] > 5.0, "{:?} is not in the top half", t);
+        }
+    }
+
+    /// Checks that every edge has one face on each side, and that all faces
+    /// point away from the origin.
+    fn check_closed(obj: &Obj) {
+        let key = |p: Point| [(p[0] * 1e4).round() as i64, (p[1] * 1e4).round() as i64, (p[2] * 1e4).round() as i64];
+        let mut edges = HashMap::new();
+        for t in &triangles(obj) {
+            let normal = normal(t);
+            assert!(dot(normal, normal) > 1e-12, "{:?} is degenerate", t);
+            assert!(dot(normal, centroid(t)) > 0.0, "{:?} is flipped", t);
+            for i in 0..3 {
+                *edges.entry((key(t[i]), key(t[(i + 1) % 3]))).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            assert!(count == 1 && edges.get(&(b, a)) == Some(&1), "edge from {:?} to {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn closed() {
+        let obj = load(cube(4));
+        for &target in &[100, 24, 12] {
+            check_closed(&run(&obj, target));
+        }
+
+        // A tetrahedron can't lose a face and stay closed.
+        let mut mesh = IndexedMesh::default();
+        mesh.positions = vec![Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, -1.0, -1.0),
+                              Vec3::new(-1.0, 1.0, -1.0), Vec3::new(-1.0, -1.0, 1.0)];
+        for face in &[[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]] {
+            mesh.add_polygon(face);
+        }
+        let simplified = simplify(&load(mesh), &SimplifyOptions::default()).unwrap();
+        assert_eq!(simplified.indices.len(), 12);
+        check_closed(&simplified);
+    }
+}
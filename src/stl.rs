@@ -0,0 +1,456 @@
+//! Reader and writer for STL, both the ASCII and the binary flavor.
+//!
+//! STL stores every triangle with its own three corners and a facet normal.
+//! On import, corners at the same position are welded into one vertex,
+//! unless the normals of their facets differ by more than the crease angle.
+//! Adjacent facets of flat and curved regions thus share their vertices,
+//! and get averaged normals, while sharp edges stay sharp. Facets without
+//! a usable normal get the one of their plane.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use na::{self, Vec3};
+
+use float::{f32_from_bits, f32_to_bits};
+use fnv::FnvBuildHasher;
+use normals::{normalize_or_zero, NormalMode};
+use obj::{self, IndexedMesh, Line, LoadOptions, Obj, ObjError, Result, WriteOptions};
+
+/// Size of the header of a binary file, including the triangle count.
+const HEADER_SIZE: usize = 84;
+/// Size of a triangle in a binary file: the normal, the corners and
+/// two bytes of attributes.
+const TRIANGLE_SIZE: usize = 50;
+
+/// Crease angle in degrees, unless `NormalMode::Creased` gives another one.
+const CREASE_ANGLE: f32 = 30.0;
+/// With `NormalMode::Flat`, corners are only welded if the normals of their
+/// facets differ by less than this, in degrees, like those rounded
+/// differently in an ASCII file.
+const FLAT_ANGLE: f32 = 0.05;
+
+/// Whether data starting with `head` is ASCII STL, binary files have no
+/// signature.
+///
+/// ASCII files start with `solid`, but so do the headers of some binary
+/// files, so when the whole size `len` is known, data of exactly the size
+/// given by its triangle count is taken as binary.
+pub fn is_stl(head: &[u8], len: Option<u64>) -> bool {
+    head.iter().skip_while(|&&b| (b as char).is_whitespace()).take(5).eq(b"solid".iter()) &&
+    (len.is_none() || binary_size(head).map(|size| size as u64) != len)
+}
+
+/// Reads binary or ASCII STL, which is told apart by `is_stl`.
+pub fn parse_bytes(data: &[u8], options: &LoadOptions) -> Result<Obj> {
+    let mut welder = Welder::new(options.normals);
+    if is_stl(data, Some(data.len() as u64)) {
+        try!(parse_ascii(&String::from_utf8_lossy(data), &mut welder));
+    } else {
+        try!(parse_binary(data, &mut welder));
+    }
+    welder.finish(options)
+}
+
+/// The size of a binary file with the triangle count from the header.
+fn binary_size(data: &[u8]) -> Option<usize> {
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+    Some(HEADER_SIZE + read_u32(&data[80..]) as usize * TRIANGLE_SIZE)
+}
+
+fn parse_binary(data: &[u8], welder: &mut Welder) -> Result<()> {
+    let size = try!(binary_size(data).ok_or_else(unexpected_eof));
+    if data.len() < size {
+        return Err(unexpected_eof());
+    }
+
+    let read_vec = |bytes: &[u8]| {
+        Vec3::new(read_f32(&bytes[0..]), read_f32(&bytes[4..]), read_f32(&bytes[8..]))
+    };
+    for triangle in data[HEADER_SIZE..size].chunks(TRIANGLE_SIZE) {
+        let corners = [read_vec(&triangle[12..]), read_vec(&triangle[24..]), read_vec(&triangle[36..])];
+        welder.add_facet(read_vec(triangle), &corners);
+    }
+    Ok(())
+}
+
+fn unexpected_eof() -> ObjError {
+    ObjError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "STL data ends before the last triangle"))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (0..4).fold(0, |bits, i| bits | (bytes[i] as u32) << (8 * i))
+}
+
+fn read_f32(bytes: &[u8]) -> f32 {
+    f32_from_bits(read_u32(bytes))
+}
+
+/// Parses `solid`s of `facet normal nx ny nz`, `outer loop`, three times
+/// `vertex x y z`, `endloop` and `endfacet`.
+fn parse_ascii(text: &str, welder: &mut Welder) -> Result<()> {
+    // The normal and the corners of the current facet.
+    let mut facet = None;
+    let mut corners = Vec::with_capacity(3);
+
+    for (i, text) in text.lines().enumerate() {
+        let line = Line { number: i + 1, text: text };
+        let mut words = text.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match (keyword, facet) {
+            ("solid", None) | ("endsolid", None) => (),
+            ("facet", None) => {
+                match words.next() {
+                    Some("normal") => facet = Some(try!(obj::parse_vec(line, words))),
+                    token => return Err(line.syntax_error(token.unwrap_or(line.end()), "`normal`")),
+                }
+                corners.clear();
+            }
+            ("outer", Some(_)) | ("endloop", Some(_)) => (),
+            ("vertex", Some(_)) => {
+                if corners.len() == 3 {
+                    return Err(line.syntax_error(keyword, "`endloop`"));
+                }
+                corners.push(try!(obj::parse_vec(line, words)));
+            }
+            ("endfacet", Some(normal)) => {
+                if corners.len() != 3 {
+                    return Err(line.syntax_error(keyword, "three `vertex` lines"));
+                }
+                welder.add_facet(normal, &corners);
+                facet = None;
+            }
+            (_, None) => return Err(line.syntax_error(keyword, "`solid`, `facet` or `endsolid`")),
+            (_, Some(_)) => return Err(line.syntax_error(keyword, "`outer loop`, `vertex`, `endloop` or `endfacet`")),
+        }
+    }
+
+    if facet.is_some() {
+        let line = Line { number: text.lines().count(), text: "" };
+        return Err(line.syntax_error(line.end(), "`endfacet`"));
+    }
+    Ok(())
+}
+
+/// Builds a mesh of facets, sharing the vertices of those which meet at
+/// less than the crease angle.
+struct Welder {
+    mesh: IndexedMesh,
+    /// The vertices at each position, by the bits of its coordinates.
+    vertices: HashMap<[u32; 3], Vec<u32>, FnvBuildHasher>,
+    /// The normal of the first facet of each vertex, which those of the
+    /// others are compared with, so that a vertex doesn't drift around a
+    /// curve. `mesh.normals` holds the sums.
+    first_normals: Vec<Vec3<f32>>,
+    /// Cosine of the crease angle.
+    min_cos: f32,
+}
+
+impl Welder {
+    fn new(mode: NormalMode) -> Welder {
+        let angle = match mode {
+            NormalMode::Flat => FLAT_ANGLE.to_radians(),
+            NormalMode::Creased(angle) => angle,
+            NormalMode::AreaWeighted | NormalMode::AngleWeighted => CREASE_ANGLE.to_radians(),
+        };
+        Welder {
+            mesh: IndexedMesh::default(),
+            vertices: HashMap::default(),
+            first_normals: Vec::new(),
+            min_cos: angle.cos(),
+        }
+    }
+
+    fn add_facet(&mut self, normal: Vec3<f32>, corners: &[Vec3<f32>]) {
+        let normal = match normalize_or_zero(normal) {
+            n if n == na::zero() => facet_normal(corners),
+            n => n,
+        };
+        let mut triangle = [0; 3];
+        for (i, &p) in corners.iter().enumerate() {
+            // Adding zero turns -0.0 into 0.0, so that the two are welded.
+            let bits = |x: f32| f32_to_bits(x + 0.0);
+            let min_cos = self.min_cos;
+            let vertices = self.vertices.entry([bits(p.x), bits(p.y), bits(p.z)]).or_insert_with(Vec::new);
+            let found = {
+                let first_normals = &self.first_normals;
+                vertices.iter().cloned().find(|&v| na::dot(&first_normals[v as usize], &normal) >= min_cos)
+            };
+            triangle[i] = match found {
+                Some(v) => {
+                    self.mesh.normals[v as usize] = self.mesh.normals[v as usize] + normal;
+                    v
+                }
+                None => {
+                    let v = self.mesh.positions.len() as u32;
+                    self.mesh.positions.push(p);
+                    self.mesh.normals.push(normal);
+                    self.first_normals.push(normal);
+                    vertices.push(v);
+                    v
+                }
+            };
+        }
+        self.mesh.add_polygon(&triangle);
+    }
+
+    /// Averages the normals of the facets around each vertex. Those of
+    /// degenerate facets stay zero, and are generated.
+    fn finish(mut self, options: &LoadOptions) -> Result<Obj> {
+        for normal in &mut self.mesh.normals {
+            *normal = normalize_or_zero(*normal);
+        }
+        self.mesh.into_obj(options)
+    }
+}
+
+/// The unit normal of the plane of a triangle, zero if it is degenerate.
+fn facet_normal(corners: &[Vec3<f32>]) -> Vec3<f32> {
+    normalize_or_zero(na::cross(&(corners[1] - corners[0]), &(corners[2] - corners[0])))
+}
+
+/// Saves the triangles of the model as binary STL.
+pub fn save_to_file<P: AsRef<Path>>(obj: &Obj, file_path: P) -> io::Result<()> {
+    let mut out = BufWriter::new(try!(File::create(file_path)));
+    write_binary(obj, &mut out)
+}
+
+/// Saves the triangles of the model as ASCII STL, naming the solid after
+/// the file.
+pub fn save_ascii_to_file<P: AsRef<Path>>(obj: &Obj, file_path: P, options: &WriteOptions) -> io::Result<()> {
+    let file_path = file_path.as_ref();
+    let name = file_path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_else(String::new);
+    let mut out = BufWriter::new(try!(File::create(file_path)));
+    write_ascii(obj, &mut out, &name, options)
+}
+
+/// Writes the triangles of the model. STL has no lines, points, colors or
+/// materials, and the facet normals are those of the triangle planes.
+pub fn write_binary<W: Write>(obj: &Obj, out: &mut W) -> io::Result<()> {
+    let mut header = [0; 80];
+    let text = b"binary STL";
+    header[..text.len()].clone_from_slice(text);
+    try!(out.write_all(&header));
+    try!(write_u32(out, (obj.indices.len() / 3) as u32));
+
+    for t in 0..obj.indices.len() / 3 {
+        let triangle = triangle(obj, t);
+        try!(write_vec(out, facet_normal(&triangle)));
+        for &p in &triangle {
+            try!(write_vec(out, p));
+        }
+        try!(out.write_all(&[0, 0]));
+    }
+    Ok(())
+}
+
+/// Writes the triangles of the model as a single ASCII `solid`.
+pub fn write_ascii<W: Write>(obj: &Obj, out: &mut W, name: &str, options: &WriteOptions) -> io::Result<()> {
+    let p = options.precision;
+    try!(writeln!(out, "solid {}", name));
+    for t in 0..obj.indices.len() / 3 {
+        let triangle = triangle(obj, t);
+        let n = facet_normal(&triangle);
+        try!(writeln!(out, "facet normal {:.*e} {:.*e} {:.*e}", p, n.x, p, n.y, p, n.z));
+        try!(writeln!(out, "outer loop"));
+        for v in &triangle {
+            try!(writeln!(out, "vertex {:.*e} {:.*e} {:.*e}", p, v.x, p, v.y, p, v.z));
+        }
+        try!(writeln!(out, "endloop"));
+        try!(writeln!(out, "endfacet"));
+    }
+    writeln!(out, "endsolid {}", name)
+}
+
+/// The corners of triangle `t`.
+fn triangle(obj: &Obj, t: usize) -> [Vec3<f32>; 3] {
+    let position = |i: usize| obj.vertices[obj.indices.get(3 * t + i) as usize].position;
+    [position(0), position(1), position(2)]
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+fn write_vec<W: Write>(out: &mut W, v: Vec3<f32>) -> io::Result<()> {
+    try!(write_u32(out, f32_to_bits(v.x)));
+    try!(write_u32(out, f32_to_bits(v.y)));
+    write_u32(out, f32_to_bits(v.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use na::Vec3;
+
+    use normals::NormalMode;
+    use obj::{LoadOptions, Obj, ObjError, WriteOptions};
+    use super::{is_stl, parse_bytes, triangle, write_ascii, write_binary};
+
+    type Facet = (Vec3<f32>, [Vec3<f32>; 3]);
+
+    fn v(x: f32, y: f32, z: f32) -> Vec3<f32> {
+        Vec3::new(x, y, z)
+    }
+
+    const UP: [f32; 3] = [0.0, 0.0, 1.0];
+
+    /// Two triangles making up the unit square.
+    fn square() -> Vec<Facet> {
+        let up = v(UP[0], UP[1], UP[2]);
+        vec![(up, [v(0.0, 0.0, 0.0), v(1.0, 0.0, 0.0), v(1.0, 1.0, 0.0)]),
+             (up, [v(0.0, 0.0, 0.0), v(1.0, 1.0, 0.0), v(0.0, 1.0, 0.0)])]
+    }
+
+    fn ascii(facets: &[Facet]) -> String {
+        let mut text = "solid test\n".to_owned();
+        for &(n, ref corners) in facets {
+            text.push_str(&format!("  facet normal {} {} {}\n    outer loop\n", n.x, n.y, n.z));
+            for c in corners {
+                text.push_str(&format!("      vertex {} {} {}\n", c.x, c.y, c.z));
+            }
+            text.push_str("    endloop\n  endfacet\n");
+        }
+        text.push_str("endsolid test\n");
+        text
+    }
+
+    fn binary(header: &[u8], facets: &[Facet]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(80, 0);
+        {
+            let mut push = |value: u32, size: usize| data.extend((0..size).map(|i| (value >> (8 * i)) as u8));
+            push(facets.len() as u32, 4);
+            for &(n, ref corners) in facets {
+                for p in Some(&n).into_iter().chain(corners) {
+                    for &x in &[p.x, p.y, p.z] {
+                        push(unsafe { mem::transmute::<f32, u32>(x) }, 4);
+                    }
+                }
+                push(0, 2);
+            }
+        }
+        data
+    }
+
+    fn parse(data: &[u8]) -> Obj {
+        parse_bytes(data, &LoadOptions::default()).unwrap()
+    }
+
+    /// The corners of every triangle.
+    fn triangles(obj: &Obj) -> Vec<[Vec3<f32>; 3]> {
+        (0..obj.indices.len() / 3).map(|t| triangle(obj, t)).collect()
+    }
+
+    #[test]
+    fn detection() {
+        let square = square();
+        let expected = square.iter().map(|f| f.1).collect::<Vec<_>>();
+        let text = ascii(&square);
+        for data in &[text.clone().into_bytes(),
+                      format!(" \r\n\t{}", text).into_bytes(),
+                      binary(b"binary", &square),
+                      // Some exporters write the name of the solid into binary headers.
+                      binary(b"solid test", &square)] {
+            assert_eq!(triangles(&parse(data)), expected);
+        }
+
+        let named = binary(b"solid test", &square);
+        assert!(is_stl(b" \n solid test", None));
+        assert!(is_stl(text.as_bytes(), Some(text.len() as u64)));
+        assert!(!is_stl(&named, Some(named.len() as u64)));
+        // Without the size the header can't be told from ASCII.
+        assert!(is_stl(&named, None));
+        assert!(!is_stl(&binary(b"binary", &square), None));
+    }
+
+    #[test]
+    fn truncated_binary() {
+        let data = binary(b"binary", &square());
+        for &len in &[data.len() - 1, 84 + 50, 84, 80] {
+            match parse_bytes(&data[..len], &LoadOptions::default()) {
+                Err(ObjError::Io(_)) => (),
+                other => panic!("expected an unexpected end of file at {} bytes, got {:?}", len, other),
+            }
+        }
+        // No triangles is fine.
+        assert_eq!(parse(&binary(b"binary", &[])).indices.len(), 0);
+    }
+
+    #[test]
+    fn zero_normal() {
+        let mut facets = square();
+        facets[0].0 = v(0.0, 0.0, 0.0);
+        for data in &[ascii(&facets).into_bytes(), binary(b"binary", &facets)] {
+            let obj = parse(data);
+            assert!(obj.normals.iter().all(|n| n.normal == v(UP[0], UP[1], UP[2])), "{:?}", obj.normals);
+            assert_eq!(obj.vertices.len(), 4);
+        }
+    }
+
+    #[test]
+    fn welding() {
+        // The corners on the diagonal are shared, also when one of them is -0.
+        let mut facets = square();
+        facets[1].1[0] = v(-0.0, 0.0, -0.0);
+        let obj = parse(ascii(&facets).as_bytes());
+        assert_eq!(obj.vertices.len(), 4);
+        assert_eq!(obj.indices.get(3), obj.indices.get(0));
+
+        // The sides of a cube meet at creases.
+        let mut cube = Vec::new();
+        for axis in 0..3 {
+            for &side in &[0.0, 1.0] {
+                let point = |a: f32, b: f32| {
+                    let mut p = [0.0; 3];
+                    p[axis] = side;
+                    p[(axis + 1) % 3] = a;
+                    p[(axis + 2) % 3] = b;
+                    v(p[0], p[1], p[2])
+                };
+                let mut normal = [0.0; 3];
+                normal[axis] = side * 2.0 - 1.0;
+                let normal = v(normal[0], normal[1], normal[2]);
+                cube.push((normal, [point(0.0, 0.0), point(1.0, 0.0), point(1.0, 1.0)]));
+                cube.push((normal, [point(0.0, 0.0), point(1.0, 1.0), point(0.0, 1.0)]));
+            }
+        }
+        assert_eq!(parse(ascii(&cube).as_bytes()).vertices.len(), 6 * 4);
+
+        // A shallow fold is smooth, unless normals are flat or the crease is sharper.
+        let fold = vec![(v(0.0, -0.087, 0.996), [v(0.0, 0.0, 0.0), v(1.0, 0.0, 0.0), v(1.0, 1.0, 0.087)]),
+                        (v(0.0, 0.0, 1.0), [v(0.0, 0.0, 0.0), v(1.0, 1.0, 0.087), v(0.0, 1.0, 0.087)])];
+        let data = ascii(&fold).into_bytes();
+        let obj = parse(&data);
+        assert_eq!(obj.vertices.len(), 4);
+        let shared = obj.normals[obj.indices.get(0) as usize].normal;
+        assert!(shared.y < 0.0 && shared.y > -0.087, "{:?}", shared);
+        for &mode in &[NormalMode::Flat, NormalMode::Creased(0.05)] {
+            let options = LoadOptions { normals: mode, ..LoadOptions::default() };
+            assert_eq!(parse_bytes(&data, &options).unwrap().vertices.len(), 6);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let obj = parse(ascii(&square()).as_bytes());
+        let mut data = Vec::new();
+        write_binary(&obj, &mut data).unwrap();
+        assert_eq!(data.len(), 84 + 2 * 50);
+        assert_eq!(triangles(&parse(&data)), triangles(&obj));
+
+        let mut data = Vec::new();
+        write_ascii(&obj, &mut data, "square", &WriteOptions::default()).unwrap();
+        let text = String::from_utf8(data).unwrap();
+        assert!(text.starts_with("solid square\n") && text.ends_with("endsolid square\n"));
+        assert_eq!(triangles(&parse(text.as_bytes())), triangles(&obj));
+    }
+}
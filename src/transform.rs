@@ -0,0 +1,170 @@
+//! Affine transforms of models, like placing glTF nodes or adjusting
+//! models after loading.
+//!
+//! Matrices are column-major, as glTF and OpenGL store them.
+
+use std::f32::consts::FRAC_PI_2;
+
+use na::{self, Vec3};
+
+use bounds::Aabb;
+use normals::normalize_or_zero;
+use obj::{Indices, Obj};
+
+pub const IDENTITY: [f32; 16] = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+
+/// Turns a model made with one axis pointing up into one with another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AxisConversion {
+    /// Z up and Y pointing away from the viewer, as most CAD tools do, to
+    /// Y up and Z pointing to the viewer.
+    ZUpToYUp,
+    YUpToZUp,
+}
+
+/// Changes to a model after loading, applied in the order of the fields.
+#[derive(Debug, Clone, Default)]
+pub struct Transform {
+    pub convert_axes: Option<AxisConversion>,
+    /// Moves the center of the bounding box to the origin.
+    pub recenter: bool,
+    /// Scales the model so that the longest side of its bounding box is one.
+    pub normalize: bool,
+    pub scale: Option<Vec3<f32>>,
+    /// Rotates counterclockwise around an axis, by an angle in radians.
+    pub rotate: Option<(Vec3<f32>, f32)>,
+    pub translate: Option<Vec3<f32>>,
+}
+
+impl Transform {
+    pub fn is_identity(&self) -> bool {
+        self.convert_axes.is_none() && !self.recenter && !self.normalize && self.scale.is_none() &&
+        self.rotate.is_none() && self.translate.is_none()
+    }
+
+    /// The matrix doing all the changes to the model, which the recentering
+    /// and normalizing depend on.
+    pub fn matrix(&self, obj: &Obj) -> [f32; 16] {
+        let mut m = match self.convert_axes {
+            // Rotations by a quarter turn around X.
+            Some(AxisConversion::ZUpToYUp) => rotation(Vec3::new(1.0, 0.0, 0.0), -FRAC_PI_2),
+            Some(AxisConversion::YUpToZUp) => rotation(Vec3::new(1.0, 0.0, 0.0), FRAC_PI_2),
+            None => IDENTITY,
+        };
+        if self.recenter || self.normalize {
+            let aabb = Aabb::of(obj.vertices.iter().map(|v| transform_point(&m, v.position)));
+            if let Some(aabb) = aabb {
+                if self.recenter {
+                    m = multiply(&translation(-aabb.center()), &m);
+                }
+                let size = aabb.size();
+                let longest = size.x.max(size.y).max(size.z);
+                if self.normalize && longest > 0.0 {
+                    m = multiply(&scaling(Vec3::new(1.0, 1.0, 1.0) / longest), &m);
+                }
+            }
+        }
+        if let Some(scale) = self.scale {
+            m = multiply(&scaling(scale), &m);
+        }
+        if let Some((axis, angle)) = self.rotate {
+            m = multiply(&rotation(axis, angle), &m);
+        }
+        if let Some(offset) = self.translate {
+            m = multiply(&translation(offset), &m);
+        }
+        m
+    }
+}
+
+/// Transforms the model as a whole.
+pub fn apply(obj: &mut Obj, transform: &Transform) {
+    if !transform.is_identity() {
+        let m = transform.matrix(obj);
+        transform_model(obj, &m);
+    }
+}
+
+/// Transforms the positions and the normals of the model. Mirroring
+/// matrices also reverse the winding of the triangles, so that their front
+/// faces stay outside.
+pub fn transform_model(obj: &mut Obj, m: &[f32; 16]) {
+    for vertex in &mut obj.vertices {
+        vertex.position = transform_point(m, vertex.position);
+    }
+    for normal in &mut obj.normals {
+        normal.normal = transform_normal(m, normal.normal);
+    }
+    if determinant(m) < 0.0 {
+        let mut indices = (0..obj.indices.len()).map(|i| obj.indices.get(i)).collect::<Vec<_>>();
+        for triangle in indices.chunks_mut(3) {
+            triangle.swap(1, 2);
+        }
+        obj.indices = Indices::new(indices, obj.vertices.len());
+    }
+}
+
+pub fn multiply(a: &[f32; 16], b: &[f32; 16]) -> [f32; 16] {
+    let mut m = [0.0; 16];
+    for column in 0..4 {
+        for row in 0..4 {
+            m[column * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[column * 4 + k]).fold(0.0, |x, y| x + y);
+        }
+    }
+    m
+}
+
+/// Translation, rotation as the quaternion `x y z w`, and scale.
+pub fn from_trs(t: &[f32], r: &[f32], s: &[f32]) -> [f32; 16] {
+    let (x, y, z, w) = (r[0], r[1], r[2], r[3]);
+    let rotation = [[1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + z * w), 2.0 * (x * z - y * w)],
+                    [2.0 * (x * y - z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + x * w)],
+                    [2.0 * (x * z + y * w), 2.0 * (y * z - x * w), 1.0 - 2.0 * (x * x + y * y)]];
+    let mut m = [0.0; 16];
+    for column in 0..3 {
+        for row in 0..3 {
+            m[column * 4 + row] = rotation[column][row] * s[column];
+        }
+    }
+    m[12] = t[0];
+    m[13] = t[1];
+    m[14] = t[2];
+    m[15] = 1.0;
+    m
+}
+
+pub fn translation(t: Vec3<f32>) -> [f32; 16] {
+    from_trs(&[t.x, t.y, t.z], &[0.0, 0.0, 0.0, 1.0], &[1.0, 1.0, 1.0])
+}
+
+pub fn scaling(s: Vec3<f32>) -> [f32; 16] {
+    from_trs(&[0.0, 0.0, 0.0], &[0.0, 0.0, 0.0, 1.0], &[s.x, s.y, s.z])
+}
+
+/// Counterclockwise around the axis, which needs not be of unit length.
+pub fn rotation(axis: Vec3<f32>, angle: f32) -> [f32; 16] {
+    let axis = normalize_or_zero(axis) * (angle / 2.0).sin();
+    from_trs(&[0.0, 0.0, 0.0], &[axis.x, axis.y, axis.z, (angle / 2.0).cos()], &[1.0, 1.0, 1.0])
+}
+
+fn columns(m: &[f32; 16]) -> [Vec3<f32>; 3] {
+    [Vec3::new(m[0], m[1], m[2]), Vec3::new(m[4], m[5], m[6]), Vec3::new(m[8], m[9], m[10])]
+}
+
+pub fn determinant(m: &[f32; 16]) -> f32 {
+    let c = columns(m);
+    na::dot(&c[0], &na::cross(&c[1], &c[2]))
+}
+
+pub fn transform_point(m: &[f32; 16], p: Vec3<f32>) -> Vec3<f32> {
+    let c = columns(m);
+    c[0] * p.x + c[1] * p.y + c[2] * p.z + Vec3::new(m[12], m[13], m[14])
+}
+
+/// Normals are transformed by the inverse transpose, whose columns are
+/// the cross products of the columns of the matrix, over the determinant.
+pub fn transform_normal(m: &[f32; 16], n: Vec3<f32>) -> Vec3<f32> {
+    let c = columns(m);
+    let n = na::cross(&c[1], &c[2]) * n.x + na::cross(&c[2], &c[0]) * n.y + na::cross(&c[0], &c[1]) * n.z;
+    normalize_or_zero(if determinant(m) < 0.0 { -n } else { n })
+}
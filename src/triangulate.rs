@@ -0,0 +1,181 @@
+use na::{self, Vec3};
+
+/// Splits a planar polygon into triangles.
+///
+/// Returns triples of indices into `polygon`, wound in the same direction
+/// as the polygon itself. Convex polygons are fanned out from the first
+/// vertex, concave ones are split by ear clipping.
+pub fn triangulate(polygon: &[Vec3<f32>]) -> Vec<[usize; 3]> {
+    let n = polygon.len();
+    if n < 3 {
+        return Vec::new();
+    }
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    let points = project(polygon);
+    let orientation = signed_area(&points).signum();
+    if orientation == 0.0 || is_convex(&points, orientation) {
+        return fan(&(0..n).collect::<Vec<_>>());
+    }
+    ear_clip(&points, orientation)
+}
+
+/// Projects the polygon onto the coordinate plane most parallel to it.
+fn project(polygon: &[Vec3<f32>]) -> Vec<(f32, f32)> {
+    let normal = newell_normal(polygon);
+    let (ax, ay, az) = (normal.x.abs(), normal.y.abs(), normal.z.abs());
+    polygon.iter()
+           .map(|p| {
+               if az >= ax && az >= ay {
+                   (p.x, p.y)
+               } else if ay >= ax {
+                   (p.z, p.x)
+               } else {
+                   (p.y, p.z)
+               }
+           })
+           .collect()
+}
+
+fn newell_normal(polygon: &[Vec3<f32>]) -> Vec3<f32> {
+    let mut normal = Vec3::new(0.0, 0.0, 0.0);
+    for i in 0..polygon.len() {
+        let a = polygon[i];
+        let b = polygon[(i + 1) % polygon.len()];
+        normal = normal + na::cross(&a, &b);
+    }
+    normal
+}
+
+fn signed_area(points: &[(f32, f32)]) -> f32 {
+    let mut area = 0.0;
+    for i in 0..points.len() {
+        let (x1, y1) = points[i];
+        let (x2, y2) = points[(i + 1) % points.len()];
+        area += x1 * y2 - x2 * y1;
+    }
+    area / 2.0
+}
+
+/// Twice the signed area of the triangle `abc`.
+fn cross(a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> f32 {
+    (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+}
+
+fn is_convex(points: &[(f32, f32)], orientation: f32) -> bool {
+    let n = points.len();
+    (0..n).all(|i| cross(points[i], points[(i + 1) % n], points[(i + 2) % n]) * orientation >= 0.0)
+}
+
+fn fan(polygon: &[usize]) -> Vec<[usize; 3]> {
+    (1..polygon.len() - 1).map(|i| [polygon[0], polygon[i], polygon[i + 1]]).collect()
+}
+
+fn ear_clip(points: &[(f32, f32)], orientation: f32) -> Vec<[usize; 3]> {
+    let mut remaining = (0..points.len()).collect::<Vec<_>>();
+    let mut result = Vec::new();
+
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let (a, b, c) = (remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]);
+            if cross(points[a], points[b], points[c]) * orientation <= 0.0 {
+                return false;
+            }
+            !remaining.iter()
+                      .filter(|&&p| p != a && p != b && p != c)
+                      .any(|&p| in_triangle(points[p], points[a], points[b], points[c], orientation))
+        });
+
+        match ear {
+            Some(i) => {
+                result.push([remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]]);
+                remaining.remove(i);
+            }
+            // Self-intersecting or degenerate polygon, there is no right answer.
+            None => break,
+        }
+    }
+
+    result.extend(fan(&remaining));
+    result
+}
+
+fn in_triangle(p: (f32, f32), a: (f32, f32), b: (f32, f32), c: (f32, f32), orientation: f32) -> bool {
+    cross(a, b, p) * orientation >= 0.0 &&
+    cross(b, c, p) * orientation >= 0.0 &&
+    cross(c, a, p) * orientation >= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use na::Vec3;
+
+    use super::triangulate;
+
+    fn polygon(points: &[(f32, f32)]) -> Vec<Vec3<f32>> {
+        points.iter().map(|&(x, y)| Vec3::new(x, y, 0.0)).collect()
+    }
+
+    /// Twice the signed area of each triangle in the xy plane.
+    fn areas(polygon: &[Vec3<f32>], triangles: &[[usize; 3]]) -> Vec<f32> {
+        triangles.iter()
+                 .map(|t| {
+                     let (a, b, c) = (polygon[t[0]], polygon[t[1]], polygon[t[2]]);
+                     (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+                 })
+                 .collect()
+    }
+
+    #[test]
+    fn convex_fan() {
+        let square = polygon(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]);
+        assert_eq!(triangulate(&square), [[0, 1, 2], [0, 2, 3]]);
+        assert!(triangulate(&square[..2]).is_empty());
+    }
+
+    #[test]
+    fn concave_ear_clipping() {
+        // An arrow head pointing up, with the notch at vertex 3.
+        let arrow = [(0.0, 0.0), (2.0, 1.0), (4.0, 0.0), (2.0, 4.0)];
+        let notched = [(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (2.0, 1.0), (0.0, 4.0)];
+        for points in &[&arrow[..], &notched[..]] {
+            for &reversed in &[false, true] {
+                let mut points = points.to_vec();
+                if reversed {
+                    points.reverse();
+                }
+                let polygon = polygon(&points);
+                let triangles = triangulate(&polygon);
+                assert_eq!(triangles.len(), points.len() - 2);
+                // Same winding as the polygon, and together they cover it.
+                let sign = if reversed { -1.0 } else { 1.0 };
+                let areas = areas(&polygon, &triangles);
+                assert!(areas.iter().all(|&a| a * sign > 0.0), "{:?} in {:?}", triangles, points);
+                let total = areas.iter().fold(0.0, |sum, &a| sum + a);
+                let expected = if points.len() == 4 { 12.0 } else { 20.0 };
+                assert_eq!(total * sign, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn projects_onto_the_polygon_plane() {
+        // The notched square, standing in the yz plane.
+        let points = [(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (2.0, 1.0), (0.0, 4.0)];
+        let polygon = points.iter().map(|&(y, z)| Vec3::new(1.0, y, z)).collect::<Vec<_>>();
+        let triangles = triangulate(&polygon);
+        assert_eq!(triangles.len(), 3);
+        // The notch can't be the tip of a fan, which would cover the notch.
+        let flat = self::polygon(&points);
+        assert!(areas(&flat, &triangles).iter().all(|&a| a > 0.0));
+    }
+
+    #[test]
+    fn degenerate_polygon() {
+        let line = polygon(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0)]);
+        assert_eq!(triangulate(&line).len(), 2);
+    }
+}
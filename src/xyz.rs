@@ -0,0 +1,191 @@
+//! Reader for point clouds stored as text, one point per line, like the
+//! `.xyz` and `.pts` files of scanners.
+//!
+//! The number of values on the first point decides what they mean:
+//!
+//! - 3: `x y z`
+//! - 4: `x y z intensity`
+//! - 6: `x y z r g b` if the last three are integers on every point and
+//!   above 1 on some, otherwise `x y z nx ny nz`
+//! - 7: `x y z intensity r g b`
+//!
+//! Intensities are ignored and colors are integers up to 255. Lines holding
+//! a single integer are the point counts `.pts` files put before each scan,
+//! and are skipped along with comments starting with `#` or `//`.
+
+use std::iter;
+
+use na::{Vec3, Vec4};
+
+use float;
+use obj::{IndexedMesh, Line, LoadOptions, Obj, Result};
+
+pub fn parse_bytes(data: &[u8], options: &LoadOptions) -> Result<Obj> {
+    let text = String::from_utf8_lossy(data);
+    let mut mesh = IndexedMesh::default();
+    let layout = Layout::detect(&text);
+    let mut tokens = Vec::new();
+
+    for (i, text) in text.lines().enumerate() {
+        let line = Line { number: i + 1, text: text };
+        if !is_point(text) {
+            continue;
+        }
+        tokens.clear();
+        tokens.extend(text.split_whitespace());
+        if tokens.len() != layout.len() {
+            let token = tokens.get(layout.len()).cloned().unwrap_or(line.end());
+            return Err(line.syntax_error(token, layout.expected()));
+        }
+        let mut values = [0.0; 7];
+        for (value, token) in values.iter_mut().zip(&tokens) {
+            *value = try!(float::parse(token).ok_or_else(|| line.syntax_error(token, "a number")));
+        }
+
+        mesh.positions.push(Vec3::new(values[0], values[1], values[2]));
+        match layout {
+            Layout::Positions | Layout::Intensities => (),
+            Layout::Normals => mesh.normals.push(Vec3::new(values[3], values[4], values[5])),
+            Layout::Colors => mesh.colors.push(color(&values[3..6])),
+            Layout::IntensitiesAndColors => mesh.colors.push(color(&values[4..7])),
+        }
+    }
+
+    mesh.points = (0..mesh.positions.len() as u32).collect();
+    mesh.into_obj(options)
+}
+
+/// Whether the line holds a point, rather than being blank, a comment or a
+/// `.pts` point count.
+fn is_point(line: &str) -> bool {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("//") {
+        return false;
+    }
+    let mut tokens = trimmed.split_whitespace();
+    let count = tokens.next().map_or(false, |token| token.parse::<u64>().is_ok());
+    !(count && tokens.next().is_none())
+}
+
+fn color(rgb: &[f32]) -> Vec4<f32> {
+    Vec4::new(rgb[0] / 255.0, rgb[1] / 255.0, rgb[2] / 255.0, 1.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    Positions,
+    Intensities,
+    Normals,
+    Colors,
+    IntensitiesAndColors,
+}
+
+impl Layout {
+    /// Decides the layout by the number of values on the first point. Unit
+    /// normals along the axes are integers too, so six values are only
+    /// colors if all points have integers up to 255 and some are above 1.
+    fn detect(text: &str) -> Layout {
+        let mut points = text.lines().filter(|line| is_point(line));
+        let first = match points.next() {
+            Some(first) => first,
+            None => return Layout::Positions,
+        };
+        match first.split_whitespace().count() {
+            4 => Layout::Intensities,
+            6 => {
+                let mut above_one = false;
+                for point in iter::once(first).chain(points) {
+                    for token in point.split_whitespace().skip(3) {
+                        match token.parse::<u8>() {
+                            Ok(value) => above_one |= value > 1,
+                            Err(_) => return Layout::Normals,
+                        }
+                    }
+                }
+                if above_one { Layout::Colors } else { Layout::Normals }
+            }
+            7 => Layout::IntensitiesAndColors,
+            // Anything else is reported when checking the number of values.
+            _ => Layout::Positions,
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Layout::Positions => 3,
+            Layout::Intensities => 4,
+            Layout::Normals | Layout::Colors => 6,
+            Layout::IntensitiesAndColors => 7,
+        }
+    }
+
+    fn expected(self) -> &'static str {
+        match self {
+            Layout::Positions => "`x y z`",
+            Layout::Intensities => "`x y z intensity`",
+            Layout::Normals => "`x y z nx ny nz`",
+            Layout::Colors => "`x y z r g b`",
+            Layout::IntensitiesAndColors => "`x y z intensity r g b`",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use na::{Vec3, Vec4};
+
+    use obj::{LoadOptions, Obj};
+    use super::{parse_bytes, Layout};
+
+    fn parse(text: &str) -> Obj {
+        parse_bytes(text.as_bytes(), &LoadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn layouts() {
+        let cases = [("0 0 0\n1 2 3\n", Layout::Positions),
+                     ("0 0 0 -1200\n", Layout::Intensities),
+                     ("0 0 0 0.5 0.5 0\n", Layout::Normals),
+                     ("0 0 0 255 128 0\n", Layout::Colors),
+                     ("0 0 0 -1200 255 128 0\n", Layout::IntensitiesAndColors),
+                     // Axis aligned normals, until a point has a color.
+                     ("0 0 0 1 0 0\n0 0 1 0 1 0\n", Layout::Normals),
+                     ("0 0 0 1 0 0\n0 0 1 0 1 0\n0 0 2 255 0 0\n", Layout::Colors),
+                     // A fraction rules out colors, wherever it is.
+                     ("0 0 0 255 0 0\n0 0 1 0.5 0.5 0\n", Layout::Normals),
+                     ("0 0 0 255 0 0\n0 0 1 0 0 256\n", Layout::Normals),
+                     ("", Layout::Positions)];
+        for &(text, layout) in &cases {
+            assert!(Layout::detect(text) == layout, "{:?}", text);
+        }
+
+        let obj = parse("0 0 0 1 0 0\n0 0 1 255 51 0\n");
+        assert_eq!(obj.colors[0].color, Vec4::new(1.0 / 255.0, 0.0, 0.0, 1.0));
+        assert_eq!(obj.colors[1].color, Vec4::new(1.0, 0.2, 0.0, 1.0));
+        let obj = parse("0 0 0 0 0 1\n");
+        assert_eq!(obj.normals[0].normal, Vec3::new(0.0, 0.0, 1.0));
+        assert!(obj.colors.is_empty());
+    }
+
+    #[test]
+    fn counts_and_comments() {
+        let obj = parse("# scan 1\n2\n0 0 0 5\n// comment\n1 0 0 5\n\n  1\n0 1 0 5\n");
+        let positions = obj.vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+        assert_eq!(positions, [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)]);
+        assert_eq!(obj.points.len(), 3);
+        assert_eq!(obj.indices.len(), 0);
+    }
+
+    #[test]
+    fn value_count_errors() {
+        for &(text, line, column) in &[("0 0 0\n1 1\n", 2, 4), ("2\n0 0 0 5\n0 0 0 5 6\n", 3, 9)] {
+            let err = parse_bytes(text.as_bytes(), &LoadOptions::default()).unwrap_err();
+            let location = err.location().unwrap();
+            assert!((location.line, location.column) == (line, column),
+                    "{:?} is reported at {}:{}",
+                    text,
+                    location.line,
+                    location.column);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+extern crate bunny;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bunny::{MeshLoader, Registry};
+use bunny::obj::{self, LoadOptions, Obj, Result};
+
+const EXTENSIONS: &'static [&'static str] = &["obj"];
+
+/// Reads OBJ like the built-in loader, counting the files it reads.
+struct CountingObj {
+    count: Arc<AtomicUsize>,
+}
+
+impl MeshLoader for CountingObj {
+    fn name(&self) -> &str {
+        "counting OBJ"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        EXTENSIONS
+    }
+
+    fn parse_bytes(&self, data: &[u8], dir: &Path, options: &LoadOptions) -> Result<Obj> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        obj::parse_bytes(data, dir, options)
+    }
+}
+
+#[test]
+fn custom_loader_wins_over_builtin() {
+    let dir = env::temp_dir().join("bunny_custom_loader");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("triangle.obj");
+    File::create(&path).unwrap().write_all(b"v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let mut registry = Registry::with_builtin_formats();
+    registry.register(Box::new(CountingObj { count: count.clone() }));
+
+    let obj = registry.load_from_file(&path, &LoadOptions::default()).unwrap();
+    assert_eq!(obj.indices.len(), 3);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert_eq!(registry.find(Some(&path), b"", None).unwrap().name(), "counting OBJ");
+}